@@ -1,10 +1,10 @@
-use bevy::{app::{App, FixedUpdate, Startup}, asset::{AssetServer, Assets, Handle}, core_pipeline::core_3d::Camera3dBundle, ecs::{event::EventReader, query::With, schedule::IntoSystemConfigs, system::{Commands, Query, Res, ResMut}}, math::Vec3, pbr::{AmbientLight, PbrBundle, StandardMaterial}, render::{mesh::{shape::Plane, Mesh}, texture::Image}, transform::components::Transform, utils::default, DefaultPlugins};
+use bevy::{app::{App, FixedUpdate, Startup}, asset::{AssetServer, Assets}, core_pipeline::core_3d::Camera3d, ecs::{event::EventReader, query::With, schedule::IntoSystemConfigs, system::{Commands, Query, Res, ResMut}}, image::Image, math::{primitives::Plane3d, Vec3}, pbr::{AmbientLight, MeshMaterial3d, StandardMaterial}, render::mesh::{Mesh, Mesh3d, Meshable}, transform::components::Transform, utils::default, DefaultPlugins};
 use bevy_h264::{decode_video, H264Decoder, H264DecoderLoading, H264Plugin, H264UpdateEvent};
 
 fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
-        .add_plugins(H264Plugin { fps: Some(120.0) })
+        .add_plugins(H264Plugin { fps: Some(120.0), validate_decoder: true, ..default() })
         .add_systems(Startup, setup)
         .add_systems(FixedUpdate, modify_materials.after(decode_video))
         .run();
@@ -23,36 +23,35 @@ fn setup(
         false,
     );
 
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Plane::from_size(5.0)),
-        material: materials.add(StandardMaterial {
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(5.0, 5.0))),
+        MeshMaterial3d(materials.add(StandardMaterial {
             base_color_texture: Some(decoder.get_render_target()),
             ..default()
-        }),
-        ..default()
-    })
-    .insert(decoder)
-    .insert(H264DecoderLoading {});
+        })),
+        decoder,
+        H264DecoderLoading {},
+    ));
 
     commands.insert_resource(AmbientLight {
         brightness: 1000.0,
         ..default()
     });
 
-    commands.spawn(Camera3dBundle {
-        transform: Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-        ..default()
-    });
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(-2.0, 2.5, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
 }
 
 fn modify_materials(
-    query: Query<&Handle<StandardMaterial>, With<H264Decoder>>,
+    query: Query<&MeshMaterial3d<StandardMaterial>, With<H264Decoder>>,
     mut update_ev: EventReader<H264UpdateEvent>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
     for update in update_ev.read() {
         if let Ok(asset_handle) = query.get(update.0) {
-            let _ = materials.get_mut(asset_handle);
+            let _ = materials.get_mut(&asset_handle.0);
         }
     }
 }
\ No newline at end of file
@@ -1,23 +1,390 @@
-use std::{collections::VecDeque, sync::{mpsc::{channel, Sender}, Arc, Mutex}};
+use std::{collections::{HashMap, VecDeque}, ops::Range, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc::{channel, Receiver, RecvTimeoutError, Sender}, Arc, Mutex}, thread::JoinHandle, time::{Duration, Instant}};
 
-use bevy_app::{FixedUpdate, Plugin, PreUpdate, Update};
-use bevy_asset::{Asset, AssetApp, AssetLoader, AssetServer, Assets, AsyncReadExt, Handle, LoadState};
-use bevy_ecs::{component::Component, entity::Entity, event::{Event, EventReader, EventWriter}, query::{Has, With, Without}, schedule::IntoSystemConfigs, system::{Commands, Query, Res, ResMut}};
+use bevy_app::{FixedUpdate, Last, Plugin, PreUpdate, Update};
+use bevy_asset::{io::{AssetSourceId, Reader}, Asset, AssetApp, AssetMode, AssetPlugin, AssetLoader, AssetPath, AssetServer, Assets, Handle, LoadContext, LoadState, RenderAssetUsages};
+use bevy_color::{Color, ColorToComponents, ColorToPacked};
+use bevy_ecs::{change_detection::DetectChangesMut, component::Component, entity::Entity, event::{Event, EventReader, EventWriter}, query::{Added, Has, With, Without}, schedule::{IntoSystemConfigs, SystemSet}, system::{Commands, Query, Res, ResMut, Resource}};
+use bevy_image::Image;
+use bevy_log::{error, warn, warn_once};
 use bevy_reflect::TypePath;
-use bevy_render::{render_asset::RenderAssetUsages, render_resource::{Extent3d, TextureDimension, TextureFormat}, texture::Image};
-use bevy_time::{Fixed, Time};
+use bevy_render::{render_resource::{Extent3d, TextureDimension, TextureFormat, TextureUsages}, view::ViewVisibility};
+use bevy_time::{Fixed, Real, Time};
+#[cfg(feature = "materials")]
+use bevy_pbr::{MeshMaterial3d, StandardMaterial};
+#[cfg(feature = "materials")]
+use bevy_sprite::{ColorMaterial, MeshMaterial2d};
+#[cfg(feature = "materials")]
+use bevy_ui::widget::ImageNode;
 use openh264::{decoder::{DecodedYUV, Decoder, DecoderConfig}, nal_units};
+use half::f16;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 const BUF_SIZE: usize = 10;
+// How long DecodeWorker::stop waits for the decode thread before abandoning it
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
 
-#[derive(Asset, TypePath)]
+const NAL_IDR: u8 = 5;
+const NAL_SEI: u8 = 6;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_FILLER: u8 = 12;
+
+#[derive(Asset, TypePath, Clone)]
 pub struct H264Video {
     buffer: Vec<Vec<u8>>,
+    // NAL index of the first slice of every picture, starting from the first keyframe
+    pictures: Vec<usize>,
+    // Picture indices of the IDR pictures
+    keyframes: Vec<usize>,
+    // NAL indices of every SPS and PPS, resent when playback jumps to a keyframe
+    parameter_sets: Vec<usize>,
+    // NAL index that playback starts and loops from
+    // This is the first keyframe including the parameter sets and SEI in front of it
+    loop_start: usize,
+    // The first keyframe decoded during load, see H264VideoLoaderSettings::decode_first_frame
+    first_frame: Option<VideoFrame>,
+    // User data unregistered SEI messages of every picture
+    sei: Vec<Vec<Sei>>,
+    track_count: usize,
+}
+
+// A user data unregistered SEI message, commonly used for timecodes and captions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sei {
+    // Identifies the format of the payload
+    pub uuid: [u8; 16],
+    pub payload: Vec<u8>,
+}
+
+impl H264Video {
+    // Builds a video from an Annex B byte stream without going through the asset loader, e.g. for tests
+    // Unlike the loader, files with several tracks are not split
+    pub fn from_annex_b(bytes: &[u8]) -> Self {
+        Self::from_nal_units(nal_units(bytes).map(|nal| nal.to_vec()).collect())
+    }
+
+    fn from_nal_units(buffer: Vec<Vec<u8>>) -> Self {
+        let mut pictures = Vec::new();
+        let mut keyframes = Vec::new();
+        let mut parameter_sets = Vec::new();
+        let mut sei = Vec::new();
+        // SEI messages that belong to the next picture
+        let mut pending_sei = Vec::new();
+        let mut loop_start = None;
+        // Start of the run of non picture NALs directly in front of the current NAL
+        let mut access_unit_start = 0;
+        for (idx, nal) in buffer.iter().enumerate() {
+            let Some(nal_type) = nal_type(nal) else { continue };
+            if nal_type == NAL_SPS || nal_type == NAL_PPS {
+                parameter_sets.push(idx);
+            }
+            if nal_type == NAL_SEI {
+                pending_sei.extend(parse_user_data_sei(nal));
+            }
+            if !is_picture_nal(nal_type) {
+                continue;
+            }
+            if loop_start.is_none() {
+                if nal_type != NAL_IDR {
+                    // Pictures before the first keyframe can not be decoded cleanly, skip them
+                    access_unit_start = idx + 1;
+                    pending_sei.clear();
+                    continue;
+                }
+                loop_start = Some(access_unit_start);
+            }
+            if is_first_slice(nal) {
+                if nal_type == NAL_IDR {
+                    keyframes.push(pictures.len());
+                }
+                pictures.push(idx);
+                sei.push(std::mem::take(&mut pending_sei));
+            }
+            access_unit_start = idx + 1;
+        }
+        Self {
+            buffer,
+            pictures,
+            keyframes,
+            parameter_sets,
+            loop_start: loop_start.unwrap_or(0),
+            first_frame: None,
+            sei,
+            track_count: 1,
+        }
+    }
+
+    // Decodes from the loop start until the first picture comes out
+    fn decode_first_frame(&self) -> Option<VideoFrame> {
+        let mut decoder = Decoder::with_config(DecoderConfig::new()).ok()?;
+        self.buffer[self.loop_start..].iter().find_map(|nal| match decoder.decode(nal.as_slice()) {
+            Ok(Some(decoded_yuv)) if !has_zero_size(&decoded_yuv) => Some(VideoFrame::from_yuv(&decoded_yuv, ConvertSettings::default())),
+            _ => None,
+        })
+    }
+
+    // The number of frames blended by a loop crossfade of the requested length, 0 if the video is too short for it
+    fn crossfade_len(&self, frames: usize) -> usize {
+        if frames * 2 <= self.frame_count() { frames } else { 0 }
+    }
+
+    // The NAL index of the first slice of a picture, None past the last picture
+    // Frame indices count pictures only, so they differ from NAL indices once parameter sets, SEI or multi slice pictures are involved
+    pub fn resolve_frame(&self, frame: usize) -> Option<usize> {
+        self.pictures.get(frame).copied()
+    }
+
+    // The NAL index where the pictures before frame end, the end of the buffer from the last picture on
+    fn resolve_frame_end(&self, frame: usize) -> usize {
+        self.resolve_frame(frame).unwrap_or(self.buffer.len())
+    }
+
+    // The number of pictures that will be presented in one loop of the video
+    pub fn frame_count(&self) -> usize {
+        self.pictures.len()
+    }
+
+    // Frame indices of the keyframes, in ascending order
+    pub fn keyframes(&self) -> &[usize] {
+        &self.keyframes
+    }
+
+    // The number of elementary streams in the file, told apart by their SPS id
+    // This asset plays the first one, the others are the labeled sub assets "track1", "track2" and so on
+    pub fn track_count(&self) -> usize {
+        self.track_count
+    }
+
+    // Files without a keyframe or with at most one picture, such as a single frame clip or a file that failed to parse
+    // Looping them would resend the same packets every tick, so they are presented once instead
+    fn is_degenerate(&self) -> bool {
+        self.keyframes.is_empty() || self.pictures.len() <= 1
+    }
+
+    // The user data unregistered SEI messages sent with a frame, empty if there are none or the frame does not exist
+    // Frame indices match H264Decoder playback, so this can be read for each presented frame
+    pub fn sei_for_frame(&self, frame: usize) -> &[Sei] {
+        self.sei.get(frame).map_or(&[], Vec::as_slice)
+    }
+
+    // The last keyframe at or before the given frame, or the first keyframe if there is none before it
+    pub fn keyframe_at_or_before(&self, frame: usize) -> usize {
+        let idx = self.keyframes.partition_point(|keyframe| *keyframe <= frame);
+        self.keyframes.get(idx.saturating_sub(1)).copied().unwrap_or(0)
+    }
 }
 
 #[derive(Default)]
-pub struct H264VideoLoader;
+pub struct H264VideoLoader {
+    progressive_loads: ProgressiveLoads,
+    // Where the default asset source reads files from, H264VideoLoaderSettings::use_index writes indices there
+    // None when that is unknown or not writable, e.g. on the web or with processed assets
+    index_root: Option<PathBuf>,
+}
+
+// NAL units of a video that is still being loaded progressively
+type ProgressiveBuffer = Arc<Mutex<Vec<Vec<u8>>>>;
+
+// Shared between the loader and the decoders so decoding can start before the load finishes
+// Entries are removed once their load is done
+#[derive(Resource, Clone, Default)]
+struct ProgressiveLoads(Arc<Mutex<HashMap<AssetPath<'static>, ProgressiveBuffer>>>);
+
+impl ProgressiveLoads {
+    fn get(&self, path: &AssetPath<'static>) -> Option<ProgressiveBuffer> {
+        self.0.lock().unwrap().get(path).cloned()
+    }
+}
+
+// Reads the video in chunks, publishing every complete NAL unit as soon as it has been read
+async fn read_progressive(reader: &mut dyn Reader, progressive: &ProgressiveBuffer) -> std::io::Result<()> {
+    use bevy_asset::AsyncReadExt;
+
+    let mut chunk = vec![0; 64 * 1024];
+    // The last NAL unit read so far, which may continue in the next chunk
+    let mut pending = Vec::new();
+    loop {
+        let read = AsyncReadExt::read(reader, &mut chunk).await?;
+        pending.extend_from_slice(&chunk[..read]);
+        let mut nals: Vec<Vec<u8>> = nal_units(pending.as_slice()).map(|nal| nal.to_vec()).collect();
+        if read == 0 {
+            progressive.lock().unwrap().append(&mut nals);
+            return Ok(());
+        }
+        pending = nals.pop().unwrap_or_default();
+        progressive.lock().unwrap().append(&mut nals);
+    }
+}
+
+// Identifies a sidecar index, "<video>.h264.idx", which lets loads skip scanning and parsing the whole file
+// After it come little endian u64s: the length and content_hash of the video file, loop_start,
+// then the NAL unit offsets, pictures, keyframes and parameter sets of H264Video, each prefixed with its length
+const INDEX_MAGIC: &[u8; 8] = b"H264IDX1";
+
+// What the sidecar index stores of a video, everything H264Video::from_nal_units would otherwise work out
+struct VideoIndex {
+    // Byte offset of every NAL unit in the file
+    nal_offsets: Vec<usize>,
+    pictures: Vec<usize>,
+    keyframes: Vec<usize>,
+    parameter_sets: Vec<usize>,
+    loop_start: usize,
+}
+
+impl VideoIndex {
+    fn new(nal_offsets: Vec<usize>, video: &H264Video) -> Self {
+        Self {
+            nal_offsets,
+            pictures: video.pictures.clone(),
+            keyframes: video.keyframes.clone(),
+            parameter_sets: video.parameter_sets.clone(),
+            loop_start: video.loop_start,
+        }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut index = INDEX_MAGIC.to_vec();
+        index.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        index.extend_from_slice(&content_hash(bytes).to_le_bytes());
+        let mut push = |value: usize| index.extend_from_slice(&(value as u64).to_le_bytes());
+        push(self.loop_start);
+        for list in [&self.nal_offsets, &self.pictures, &self.keyframes, &self.parameter_sets] {
+            push(list.len());
+            list.iter().for_each(|&value| push(value));
+        }
+        index
+    }
+
+    // None if the index is malformed or was generated for a different file
+    fn decode(index: &[u8], bytes: &[u8]) -> Option<Self> {
+        let mut values = index.strip_prefix(INDEX_MAGIC)?
+            .chunks(8)
+            .map(|value| Some(u64::from_le_bytes(value.try_into().ok()?)));
+        let len = bytes.len();
+        if values.next().flatten()? != len as u64 || values.next().flatten()? != content_hash(bytes) {
+            return None;
+        }
+        let mut next = || values.next().flatten().map(|value| value as usize);
+        let loop_start = next()?;
+        let mut list = || {
+            let count = next()?;
+            // The count comes from the file, so it is not trusted with an allocation up front
+            (0..count).map(|_| next()).collect::<Option<Vec<usize>>>()
+        };
+        let index = Self { loop_start, nal_offsets: list()?, pictures: list()?, keyframes: list()?, parameter_sets: list()? };
+        let increasing = |list: &[usize], bound: usize| list.windows(2).all(|pair| pair[0] < pair[1]) && list.last().is_none_or(|&last| last < bound);
+        let nal_count = index.nal_offsets.len();
+        let valid = increasing(&index.nal_offsets, len)
+            && increasing(&index.pictures, nal_count)
+            && increasing(&index.parameter_sets, nal_count)
+            && increasing(&index.keyframes, index.pictures.len())
+            && index.loop_start <= nal_count;
+        valid.then_some(index)
+    }
+}
+
+// FNV-1a of the whole file, so a file that was re-encoded to the same size does not match the index of the old one
+fn content_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+impl H264Video {
+    // Rebuilds a video from its index without parsing the slices
+    // SEI is not part of the index, only the SEI NAL units themselves are parsed to attach it to its pictures
+    fn from_index(buffer: Vec<Vec<u8>>, index: VideoIndex) -> Self {
+        let mut sei = vec![Vec::new(); index.pictures.len()];
+        for (idx, nal) in buffer.iter().enumerate().skip(index.loop_start) {
+            if nal_type(nal) == Some(NAL_SEI) {
+                let picture = index.pictures.partition_point(|&picture| picture < idx);
+                if let Some(messages) = sei.get_mut(picture) {
+                    messages.extend(parse_user_data_sei(nal));
+                }
+            }
+        }
+        Self {
+            buffer,
+            pictures: index.pictures,
+            keyframes: index.keyframes,
+            parameter_sets: index.parameter_sets,
+            loop_start: index.loop_start,
+            first_frame: None,
+            sei,
+            track_count: 1,
+        }
+    }
+}
+
+// The sidecar index of an Annex B file, for generating indices ahead of time when the asset folder is not writable
+// None for files with several tracks, those are always scanned
+pub fn h264_index(bytes: &[u8]) -> Option<Vec<u8>> {
+    let nal_offsets = nal_offsets(bytes);
+    let mut tracks = split_tracks(split_at_offsets(bytes, &nal_offsets));
+    if tracks.len() > 1 {
+        return None;
+    }
+    let video = H264Video::from_nal_units(tracks.pop().unwrap_or_default());
+    Some(VideoIndex::new(nal_offsets, &video).encode(bytes))
+}
+
+fn nal_offsets(bytes: &[u8]) -> Vec<usize> {
+    nal_units(bytes).map(|nal| nal.as_ptr() as usize - bytes.as_ptr() as usize).collect()
+}
+
+// Splits a file into NAL units at the offsets of its index, each ends where the next one starts
+fn split_at_offsets(bytes: &[u8], offsets: &[usize]) -> Vec<Vec<u8>> {
+    offsets.iter().enumerate()
+        .map(|(idx, &start)| bytes[start..offsets.get(idx + 1).copied().unwrap_or(bytes.len())].to_vec())
+        .collect()
+}
+
+// "<video>.h264.idx" next to the video
+fn index_path(load_context: &LoadContext) -> PathBuf {
+    let mut index_path = load_context.path().as_os_str().to_owned();
+    index_path.push(".idx");
+    PathBuf::from(index_path)
+}
+
+// The video rebuilt from its sidecar index, None if there is no valid one
+async fn read_indexed(bytes: &[u8], load_context: &mut LoadContext<'_>) -> Option<H264Video> {
+    let index_path = index_path(load_context);
+    let index = load_context.read_asset_bytes(index_path.clone()).await.ok()?;
+    let Some(index) = VideoIndex::decode(&index, bytes) else {
+        warn!("Ignoring the index {:?}, it does not match the video", index_path);
+        return None;
+    };
+    let buffer = split_at_offsets(bytes, &index.nal_offsets);
+    Some(H264Video::from_index(buffer, index))
+}
+
+// Writes the index for the next load, only for videos from the default source on disk, see H264VideoLoader::index_root
+// Failing is fine, the file is scanned again on the next load
+fn write_index(index_root: Option<&Path>, load_context: &LoadContext, index: &[u8]) {
+    let Some(index_root) = index_root else { return };
+    if *load_context.asset_path().source() != AssetSourceId::Default {
+        return;
+    }
+    let path = index_root.join(index_path(load_context));
+    if let Err(err) = std::fs::write(&path, index) {
+        warn!("Could not write the index {:?}: {}", path, err);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct H264VideoLoaderSettings {
+    // Decode the first keyframe while loading so decoders can present it as soon as the video is loaded,
+    // instead of showing the placeholder until the decode thread catches up
+    // It is converted with the default ConvertOptions, decoders with other options or an alpha video wait for the thread instead
+    pub decode_first_frame: bool,
+    // Lets decoders start from the first keyframe while the rest of the file is still loading
+    // Useful for large files, where reading the whole file first delays the first frame noticeably
+    pub progressive: bool,
+    // Rebuild the video from the sidecar "<video>.h264.idx" instead of scanning and parsing the file, see h264_index
+    // A missing or outdated index is generated on the first load if the video comes from the default asset source on disk
+    // Files with several tracks are never indexed
+    // Ignored for progressive loads, which scan the file while reading it anyway
+    pub use_index: bool,
+}
 
 #[derive(Debug, Error)]
 pub enum H264VideoLoaderError {
@@ -28,24 +395,66 @@ pub enum H264VideoLoaderError {
 impl AssetLoader for H264VideoLoader{
     type Asset = H264Video;
 
-    type Settings = ();
+    type Settings = H264VideoLoaderSettings;
 
     type Error = H264VideoLoaderError;
 
-    fn load<'a>(
-        &'a self,
-        reader: &'a mut bevy_asset::io::Reader,
-        _settings: &'a Self::Settings,
-        _load_context: &'a mut bevy_asset::LoadContext,
-    ) -> bevy_asset::BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
-        Box::pin(async move {
-            let mut bytes = Vec::new();
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        settings: &Self::Settings,
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<Self::Asset, Self::Error> {
+        let mut bytes = Vec::new();
+        // Filled when the file is scanned for its index
+        let mut offsets = Vec::new();
+        let buffer = if settings.progressive {
+            let path = load_context.asset_path().clone();
+            let progressive = ProgressiveBuffer::default();
+            self.progressive_loads.0.lock().unwrap().insert(path.clone(), progressive.clone());
+            let result = read_progressive(reader, &progressive).await;
+            self.progressive_loads.0.lock().unwrap().remove(&path);
+            result?;
+            std::mem::take(&mut *progressive.lock().unwrap())
+        } else {
             reader.read_to_end(&mut bytes).await?;
-            let buffer = nal_units(bytes.as_slice()).map(|nal| nal.to_vec()).collect();
-            Ok(H264Video {
-                buffer,
-            })
-        })
+            if settings.use_index {
+                if let Some(mut video) = read_indexed(&bytes, load_context).await {
+                    if settings.decode_first_frame {
+                        video.first_frame = video.decode_first_frame();
+                    }
+                    return Ok(video);
+                }
+                offsets = nal_offsets(&bytes);
+                split_at_offsets(&bytes, &offsets)
+            } else {
+                nal_units(bytes.as_slice()).map(|nal| nal.to_vec()).collect()
+            }
+        };
+        let load_track = |buffer| {
+            let mut video = H264Video::from_nal_units(buffer);
+            if settings.decode_first_frame {
+                video.first_frame = video.decode_first_frame();
+            }
+            video
+        };
+        // Progressive decoders already play the NAL units in file order, so those files are not split
+        if settings.progressive {
+            return Ok(load_track(buffer));
+        }
+        let mut tracks = split_tracks(buffer).into_iter();
+        let mut video = load_track(tracks.next().unwrap_or_default());
+        video.track_count = tracks.len() + 1;
+        if video.track_count > 1 {
+            for (idx, track) in tracks.enumerate() {
+                load_context.add_labeled_asset(format!("track{}", idx + 1), load_track(track));
+            }
+        } else if settings.use_index {
+            // Files with several tracks are not indexed
+            let index = VideoIndex::new(offsets, &video).encode(&bytes);
+            write_index(self.index_root.as_deref(), load_context, &index);
+        }
+        Ok(video)
     }
 
     fn extensions(&self) -> &[&str] {
@@ -53,284 +462,3190 @@ impl AssetLoader for H264VideoLoader{
     }
 }
 
+// Where a picture belongs in playback
+// loop_idx counts loops, restarts and seeks, so frames queued before one of those can be told apart from the current ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct FramePosition {
+    loop_idx: usize,
+    picture: usize,
+}
+
 enum DecoderMessage {
-    Frame(Vec<u8>),
+    // The position is set on the first slice of a picture, frames decoded after it carry it
+    // The instant is when the packet was sent, for latency measurement
+    Frame(Vec<u8>, Option<FramePosition>, Instant),
+    // A packet of the alpha video, sent ahead of the first slice of the color picture it belongs to
+    Alpha(Vec<u8>),
+    // Drops every frame decoded from the packets sent before this one
+    ClearFrames,
     Stop,
 }
 
-struct VideoFrame {
+// A decoded picture, converted to the decoder's OutputFormat
+#[derive(Clone)]
+pub struct VideoFrame {
     buffer: Vec<u8>,
     width: usize,
     height: usize,
+    format: OutputFormat,
+    position: Option<FramePosition>,
+    // When the packet that completed this frame was sent to the decode thread
+    sent_at: Option<Instant>,
 }
 
-#[derive(Component)]
-pub struct H264Decoder {
-    video: Handle<H264Video>,
-    render_target: Handle<Image>,
-    repeat: bool,
-    
-    next_frame: usize,
-    frame_count: usize,
-
-    frame_idx: usize,
+impl VideoFrame {
+    // Tightly packed rows of pixels in the format's channel order
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
 
-    sender: Mutex<Sender<DecoderMessage>>,
-    next_frame_rgb8: Arc<Mutex<VecDeque<VideoFrame>>>,
-}
+    pub fn into_data(self) -> Vec<u8> {
+        self.buffer
+    }
 
-impl H264Decoder {
-    pub fn new(images: &mut ResMut<Assets<Image>>, video: Handle<H264Video>, repeat: bool) -> Self {
-        let render_target = images.add(Image::new_fill(
-            Extent3d {
-                width: 12,
-                height: 12,
-                depth_or_array_layers: 1,
-            }, 
-            TextureDimension::D2,
-            &[0, 0, 0, 0],
-            TextureFormat::Bgra8UnormSrgb, 
-            RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
-        ));
-        let (sender, receiver) = channel::<DecoderMessage>();
-        let next_frame_rgb8 = Arc::new(Mutex::new(VecDeque::<VideoFrame>::with_capacity(BUF_SIZE + 1)));
-        std::thread::spawn({
-            let next_frame_rgb8 = next_frame_rgb8.clone();
-            move || {
-                let cfg = DecoderConfig::new();
-                let mut decoder = Decoder::with_config(cfg).expect("Failed to create decoder");
-                while let Ok(video_packet) = receiver.recv() {
-                    let video_packet = match video_packet {
-                        DecoderMessage::Frame(vp) => vp,
-                        DecoderMessage::Stop => return,
-                    };
-                    let decoded_yuv = decoder.decode(video_packet.as_slice());
-                    let decoded_yuv = match decoded_yuv {
-                        Ok(decoded) => decoded,
-                        Err(_) => {continue},
-                    };
-                    let Some(decoded_yuv) = decoded_yuv else {continue};
-
-                    let (width, height) = decoded_yuv.dimension_rgb();
-                    let buffer = decoded_yuv.write_bgra8();
-                    let frame = VideoFrame {
-                        buffer,
-                        width,
-                        height,
-                    };
-                    if let Ok(mut queue) = next_frame_rgb8.lock() {
-                        queue.push_back(frame);
-                    }
-                }
-            }
-        });
-        Self {
-            video,
-            render_target: render_target.clone(),
-            repeat,
-            next_frame: 0,
-            frame_count: 0,
-            frame_idx: 0,
-            sender: Mutex::new(sender),
-            next_frame_rgb8,
-        }
+    pub fn width(&self) -> usize {
+        self.width
     }
 
-    pub fn get_render_target(&self) -> Handle<Image> {
-        self.render_target.clone()
+    pub fn height(&self) -> usize {
+        self.height
     }
 
-    fn add_video_packet(&self, video_packet: Vec<u8>) {
-        self.sender.lock().expect("Could not get lock on sender").send(DecoderMessage::Frame(video_packet)).expect("Could not send packet to decoder");
+    pub fn format(&self) -> OutputFormat {
+        self.format
     }
 
-    fn take_frame(&mut self) -> Option<VideoFrame> {
-        if let Ok(mut queue) = self.next_frame_rgb8.lock() {
-            queue.pop_front()
-        } else {
-            None
+    fn from_yuv(decoded_yuv: &DecodedYUV, settings: ConvertSettings) -> Self {
+        let (width, height) = decoded_yuv.dimension_rgb();
+        Self {
+            buffer: convert_yuv(&YuvPlanes::new(decoded_yuv), settings.options, settings.output_format.into()),
+            width,
+            height,
+            format: settings.output_format,
+            position: None,
+            sent_at: None,
         }
     }
 }
 
-impl Drop for H264Decoder {
-    fn drop(&mut self) {
-        self.sender.lock().expect("Could not get lock on sender").send(DecoderMessage::Stop).expect("Could not send end packet to decoder");
+// Encodes frames solid color pictures as an Annex B stream, for tests that need a small valid video without fixture files
+// Odd sizes are rounded up to even, as H264 requires. Load it with H264Video::from_annex_b or write it to a .h264 file
+#[cfg(any(test, feature = "test-utils"))]
+pub fn solid_color_h264(width: u32, height: u32, frames: usize, color: Color) -> Result<Vec<u8>, openh264::Error> {
+    use openh264::{encoder::{Encoder, EncoderConfig}, formats::YUVBuffer};
+
+    let (width, height) = (width.max(2).next_multiple_of(2), height.max(2).next_multiple_of(2));
+    let [r, g, b, _] = color.to_srgba().to_u8_array();
+    let rgb = [r, g, b].repeat(width as usize * height as usize);
+    let yuv = YUVBuffer::with_rgb(width as usize, height as usize, &rgb);
+    let mut encoder = Encoder::with_config(EncoderConfig::new(width, height))?;
+    let mut stream = Vec::new();
+    for _ in 0..frames {
+        encoder.encode(&yuv)?.write_vec(&mut stream);
     }
+    Ok(stream)
 }
 
-// Add this component to an entity that is loading a video from the asset server
-#[derive(Component)]
-pub struct H264DecoderLoading;
+// Decode rate over the last second, see H264Decoder::throughput
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Throughput {
+    pub pictures_per_sec: f64,
+    // Bytes of H264 input consumed by the decoder
+    pub bytes_per_sec: f64,
+}
 
-// This update is called whenever a decoder has updated the render target image
-// Make sure all materials that read the image are modified
-#[derive(Event)]
-pub struct H264UpdateEvent(pub Entity);
+// Running totals of the decode thread
+#[derive(Default)]
+struct DecodeCounters {
+    pictures: AtomicUsize,
+    bytes: AtomicUsize,
+    // Packets the decoder failed on since the last picture, reset by whoever handles them
+    consecutive_errors: AtomicUsize,
+}
 
-#[derive(Component)]
-pub struct H264DecoderPause;
+// Samples DecodeCounters every tick and computes the Throughput over a sliding window
+#[derive(Default)]
+struct ThroughputWindow {
+    // Time in seconds, pictures and bytes of every sample
+    samples: VecDeque<(f64, usize, usize)>,
+    throughput: Throughput,
+}
 
-// Remove the loading flag once a video is done loading
-fn begin_decode(
-    mut commands: Commands,
-    mut query: Query<(Entity, &mut H264Decoder), With<H264DecoderLoading>>,
-    asset_server: Res<AssetServer>,
-    videos: Res<Assets<H264Video>>,
-) {
-    for (entity, mut decoder) in query.iter_mut() {
-        // If it is still loading, then ignore
-        if match asset_server.get_load_state(&decoder.video) {
-            Some(load_state) => matches!(load_state, LoadState::Loading),
-            _ => false,
-        } {
-            continue;
+impl ThroughputWindow {
+    const WINDOW_SECS: f64 = 1.0;
+
+    fn update(&mut self, now: f64, pictures: usize, bytes: usize) {
+        self.samples.push_back((now, pictures, bytes));
+        // Keep the newest sample from before the window so it is fully covered
+        while self.samples.get(1).is_some_and(|(time, ..)| now - time >= Self::WINDOW_SECS) {
+            self.samples.pop_front();
         }
-        commands.entity(entity).remove::<H264DecoderLoading>();
-        
-        if match asset_server.get_load_state(&decoder.video) {
-            Some(load_state) => matches!(load_state, LoadState::Failed) || matches!(load_state, LoadState::NotLoaded),
-            _ => false,
-        } {
-            commands.entity(entity).remove::<H264Decoder>();
-        } else {
-            if let Some(video) = videos.get(&decoder.video) {
-                // Assume 1 slice per frame
-                decoder.frame_count = video.buffer.len();
-            }
+        let Some(&(start, start_pictures, start_bytes)) = self.samples.front() else { return };
+        let elapsed = now - start;
+        if elapsed > 0.0 {
+            self.throughput = Throughput {
+                pictures_per_sec: (pictures - start_pictures) as f64 / elapsed,
+                bytes_per_sec: (bytes - start_bytes) as f64 / elapsed,
+            };
         }
     }
 }
 
-pub fn decode_video(
-    mut commands: Commands,
-    mut query: Query<(Entity, &mut H264Decoder), (Without<H264DecoderPause>, Without<H264DecoderLoading>)>,
-    mut images: ResMut<Assets<Image>>,
-    mut update_ev: EventWriter<H264UpdateEvent>,
-) {
-    for (entity, mut decoder) in query.iter_mut() {
-        if let Some(frame) = decoder.take_frame() {
-            let image = match images.get_mut(&decoder.render_target) {
-                Some(image) => image,
-                None => {
-                    // Render target is missing, remove self
-                    println!("Render target is missing");
-                    commands.entity(entity).remove::<H264Decoder>();
-                    continue;
-                }
-            };
-            if image.texture_descriptor.size.width != frame.width as u32 || image.texture_descriptor.size.height != frame.height as u32 {
-                image.resize(Extent3d { width: frame.width as u32, height: frame.height as u32, depth_or_array_layers: 1 });
-            }
+// Average time between sending a packet and presenting the frame it produced, over the last frames
+#[derive(Default)]
+struct LatencyWindow {
+    samples: VecDeque<Duration>,
+}
 
-            image.data = frame.buffer;
+impl LatencyWindow {
+    const SAMPLES: usize = 30;
 
-            // Send the event
-            update_ev.send(H264UpdateEvent(entity));
-            decoder.next_frame = decoder.next_frame + 1;
-            if decoder.next_frame >= decoder.frame_count {
-                decoder.next_frame = 0;
-                if !decoder.repeat {
-                    commands.entity(entity).insert(H264DecoderPause {});
-                }
-            }                
+    fn record(&mut self, frame: &VideoFrame) {
+        let Some(sent_at) = frame.sent_at else { return };
+        if self.samples.len() == Self::SAMPLES {
+            self.samples.pop_front();
         }
-        // If frame is missed, wait until next game tick
+        self.samples.push_back(sent_at.elapsed());
     }
-}
 
-fn push_packet(
-    mut query: Query<&mut H264Decoder, (Without<H264DecoderLoading>, Without<H264DecoderPause>)>,
-    videos: Res<Assets<H264Video>>,
-) {
-    for mut decoder in query.iter_mut() {
-        // Only push more packets if there is space in the buffer
-        let mut buffer_size = decoder.next_frame_rgb8.lock().unwrap().len();
-        if let Some(video) = videos.get(&decoder.video) {
-            while buffer_size < BUF_SIZE {
-                decoder.add_video_packet(video.buffer[decoder.frame_idx].clone());
-                decoder.frame_idx = (decoder.frame_idx + 1) % video.buffer.len();
-                buffer_size += 1;
-            }
+    fn average_ms(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
         }
+        Some(self.samples.iter().sum::<Duration>().as_secs_f64() * 1000.0 / self.samples.len() as f64)
     }
 }
 
-// This event makes no garuntees on what the real frame will be
-// If the video is not suppose to restart, then you should make sure it has been paused (ideally for a short amount of time)
-// If the video is paused it will clear out the image queue
-#[derive(Event)]
-pub struct H264RestartEvent(Entity);
+// Where packets are decoded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DecodeMode {
+    // On a background thread per decoder
+    #[default]
+    Threaded,
+    // Inline on the main schedule when packets are pushed, without a thread or channel
+    // Meant for many tiny videos, where a thread per video costs more than the decoding itself
+    Sync,
+}
 
-fn restart_video(
-    mut query: Query<(&mut H264Decoder, Has<H264DecoderPause>), Without<H264DecoderLoading>>,
-    mut restart_ev: EventReader<H264RestartEvent>,
-) {
-    for event in restart_ev.read() {
-        if let Ok((mut decoder, is_paused)) = query.get_mut(event.0) {
-            decoder.frame_idx = 0;
-            decoder.next_frame = 0;
-            if is_paused {
-                decoder.next_frame_rgb8.lock().unwrap().clear();
+// The luma of the latest alpha picture, tightly packed in the row order of the converted frames
+struct AlphaPlane {
+    width: usize,
+    height: usize,
+    values: Vec<u8>,
+}
+
+impl AlphaPlane {
+    // The range option applies to the alpha video too, and flip_y keeps it aligned with the color rows
+    fn from_yuv(decoded_yuv: &DecodedYUV, options: ConvertOptions) -> Self {
+        let (width, height) = decoded_yuv.dimension_rgb();
+        let stride = decoded_yuv.strides_yuv().0;
+        let luma = decoded_yuv.y_with_stride();
+        let mut values = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = if options.flip_y { height - 1 - y } else { y };
+            values.extend(luma[row * stride..row * stride + width].iter().map(|&value| match options.range {
+                ColorRange::Full => value,
+                ColorRange::Limited => ((value as f32 - 16.0) * 255.0 / 219.0).clamp(0.0, 255.0) as u8,
+            }));
+        }
+        Self { width, height, values }
+    }
+
+    // Writes the plane into the alpha channel of a converted frame of the same size
+    fn apply(&self, frame: &mut VideoFrame) {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            warn_once!("Alpha video is {}x{} but the video is {}x{}, ignoring its alpha", self.width, self.height, frame.width, frame.height);
+            return;
+        }
+        match frame.format {
+            OutputFormat::Bgra8UnormSrgb | OutputFormat::Rgba8UnormSrgb | OutputFormat::Rgba8Unorm => {
+                for (pixel, &alpha) in frame.buffer.chunks_exact_mut(4).zip(&self.values) {
+                    pixel[3] = alpha;
+                }
+            }
+            OutputFormat::Rgba16Float => {
+                // Alpha is linear, so it is not converted like the color channels
+                for (pixel, &alpha) in frame.buffer.chunks_exact_mut(8).zip(&self.values) {
+                    pixel[6..8].copy_from_slice(&f16::from_f32(alpha as f32 / 255.0).to_le_bytes());
+                }
             }
         }
     }
 }
 
-// Skips a step of copying by just creating the buffer in the right format
-trait Bgra8Writer {
-    fn write_bgra8(&self) -> Vec<u8>;
+// Decodes the alpha video next to the color video, see ConvertSettings::alpha
+struct AlphaDecode {
+    decoder: Decoder,
+    plane: Option<AlphaPlane>,
 }
-impl<'a> Bgra8Writer for DecodedYUV<'a> {
-    fn write_bgra8(&self) -> Vec<u8> {
-        let dim = self.dimension_rgb();
-        let strides = self.strides_yuv();
-        let size = dim.0 * dim.1 * 4;
-
-        let mut result = vec![0; size];
-
-        for y in 0..dim.1 {
-            for x in 0..dim.0 {
-                let base_tgt = (y * dim.0 + x) * 4;
-                let base_y = y * strides.0 + x;
-                let base_u = (y / 2 * strides.1) + (x / 2);
-                let base_v = (y / 2 * strides.2) + (x / 2);
 
-                let bgra_pixel = &mut result[base_tgt..base_tgt + 4];
+// Decodes packets into the frame queue, on the decode thread or inline with DecodeMode::Sync
+struct DecodeState {
+    decoder: Decoder,
+    alpha: Option<AlphaDecode>,
+    settings: ConvertSettings,
+    // Position of the picture currently being decoded
+    position: Option<FramePosition>,
+    next_frame_rgb8: Arc<Mutex<VecDeque<VideoFrame>>>,
+    clears_handled: Arc<AtomicUsize>,
+    counters: Arc<DecodeCounters>,
+    // Set by DecodeWorker::stop, checked between messages and after every decode
+    cancelled: Arc<AtomicBool>,
+}
 
-                let y = self.y_with_stride()[base_y] as f32;
-                let u = self.u_with_stride()[base_u] as f32;
-                let v = self.v_with_stride()[base_v] as f32;
+impl DecodeState {
+    fn new(settings: ConvertSettings, next_frame_rgb8: Arc<Mutex<VecDeque<VideoFrame>>>, clears_handled: Arc<AtomicUsize>, counters: Arc<DecodeCounters>, cancelled: Arc<AtomicBool>) -> Result<Self, openh264::Error> {
+        let alpha = match settings.alpha {
+            true => Some(AlphaDecode { decoder: Decoder::with_config(DecoderConfig::new())?, plane: None }),
+            false => None,
+        };
+        Ok(Self {
+            decoder: Decoder::with_config(DecoderConfig::new())?,
+            alpha,
+            settings,
+            position: None,
+            next_frame_rgb8,
+            clears_handled,
+            counters,
+            cancelled,
+        })
+    }
 
-                bgra_pixel[2] = (y + 1.402 * (v - 128.0)) as u8;
-                bgra_pixel[1] = (y - 0.344 * (u - 128.0) - 0.714 * (v - 128.0)) as u8;
-                bgra_pixel[0] = (y + 1.772 * (u - 128.0)) as u8;
-                bgra_pixel[3] = 255;
+    // Returns false once the decoder is told to stop
+    fn handle(&mut self, message: DecoderMessage) -> bool {
+        if self.cancelled.load(Ordering::Acquire) {
+            return false;
+        }
+        let (video_packet, sent_at) = match message {
+            DecoderMessage::Frame(vp, picture_position, sent_at) => {
+                if picture_position.is_some() {
+                    self.position = picture_position;
+                }
+                (vp, sent_at)
+            }
+            DecoderMessage::Alpha(packet) => {
+                let Some(alpha) = &mut self.alpha else { return true };
+                match alpha.decoder.decode(packet.as_slice()) {
+                    Ok(Some(decoded_yuv)) if !has_zero_size(&decoded_yuv) => {
+                        alpha.plane = Some(AlphaPlane::from_yuv(&decoded_yuv, self.settings.options));
+                    }
+                    Ok(_) => {}
+                    Err(_) => warn_once!("Could not decode a packet of the alpha video"),
+                }
+                return true;
+            }
+            DecoderMessage::ClearFrames => {
+                self.position = None;
+                if let Ok(mut queue) = self.next_frame_rgb8.lock() {
+                    queue.clear();
+                    self.clears_handled.fetch_add(1, Ordering::Release);
+                }
+                return true;
             }
+            DecoderMessage::Stop => return false,
+        };
+        self.counters.bytes.fetch_add(video_packet.len(), Ordering::Relaxed);
+        let decoded_yuv = match self.decoder.decode(video_packet.as_slice()) {
+            Ok(decoded) => decoded,
+            Err(_) => {
+                self.counters.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+                return true;
+            }
+        };
+        // A decode that took long enough for the worker to give up on this thread must not convert or queue anything,
+        // the queue may already belong to its replacement
+        if self.cancelled.load(Ordering::Acquire) {
+            return false;
+        }
+        let Some(decoded_yuv) = decoded_yuv else { return true };
+        if has_zero_size(&decoded_yuv) {
+            warn_once!("Decoder produced a frame without pixels, skipping it");
+            return true;
+        }
+
+        self.counters.pictures.fetch_add(1, Ordering::Relaxed);
+        self.counters.consecutive_errors.store(0, Ordering::Relaxed);
+        let mut frame = VideoFrame::from_yuv(&decoded_yuv, self.settings);
+        if let Some(plane) = self.alpha.as_ref().and_then(|alpha| alpha.plane.as_ref()) {
+            plane.apply(&mut frame);
         }
-        result
+        frame.position = self.position;
+        frame.sent_at = Some(sent_at);
+        if let Ok(mut queue) = self.next_frame_rgb8.lock() {
+            queue.push_back(frame);
+        }
+        true
     }
 }
 
-// Sets the fixed timestep to the given FPS
-// If fixed timestep is already set, then set this to None
-// All videos will play at the same FPS
-pub struct H264Plugin {
-    pub fps: Option<f64>,
+// Owns the background decode thread and the queue of frames it has produced
+// Shared by H264Decoder and H264StreamDecoder
+struct DecodeWorker {
+    // None while the thread is stopped, packets sent in the meantime are dropped
+    sender: Mutex<Option<Sender<DecoderMessage>>>,
+    thread: Option<JoinHandle<()>>,
+    // Disconnects when the thread exits, so stop can wait for it with a timeout
+    exited: Option<Receiver<()>>,
+    mode: DecodeMode,
+    // The decoder of DecodeMode::Sync, used in place of the thread
+    inline: Option<Mutex<DecodeState>>,
+    thread_name: String,
+    settings: ConvertSettings,
+    next_frame_rgb8: Arc<Mutex<VecDeque<VideoFrame>>>,
+    // ClearFrames messages sent, and handled by the thread
+    // While they differ everything in the queue is stale
+    clears_sent: usize,
+    clears_handled: Arc<AtomicUsize>,
+    counters: Arc<DecodeCounters>,
+    // Cancels the current thread, replaced on every start so an abandoned thread stays cancelled
+    cancelled: Arc<AtomicBool>,
+    throughput: ThroughputWindow,
 }
 
-impl Plugin for H264Plugin {
-    fn build(&self, app: &mut bevy_app::App) {
-        if let Some(fps) = self.fps {
-            app.insert_resource(Time::<Fixed>::from_hz(fps));
+impl DecodeWorker {
+    // The thread is named so it can be told apart in profilers
+    fn spawn(thread_name: String, settings: ConvertSettings, mode: DecodeMode) -> std::io::Result<Self> {
+        let mut worker = Self {
+            sender: Mutex::new(None),
+            thread: None,
+            exited: None,
+            mode,
+            inline: None,
+            thread_name,
+            settings,
+            next_frame_rgb8: Arc::new(Mutex::new(VecDeque::<VideoFrame>::with_capacity(BUF_SIZE + 1))),
+            clears_sent: 0,
+            clears_handled: Arc::new(AtomicUsize::new(0)),
+            counters: Arc::new(DecodeCounters::default()),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            throughput: ThroughputWindow::default(),
+        };
+        worker.start()?;
+        Ok(worker)
+    }
+
+    fn is_running(&self) -> bool {
+        self.thread.is_some() || self.inline.is_some()
+    }
+
+    // Spawns the decode thread if it is not running
+    fn start(&mut self) -> std::io::Result<()> {
+        if self.is_running() {
+            return Ok(());
+        }
+        self.cancelled = Arc::new(AtomicBool::new(false));
+        if self.mode == DecodeMode::Sync {
+            let state = DecodeState::new(self.settings, self.next_frame_rgb8.clone(), self.clears_handled.clone(), self.counters.clone(), self.cancelled.clone())
+                .map_err(|err| std::io::Error::other(err.to_string()))?;
+            self.inline = Some(Mutex::new(state));
+            return Ok(());
+        }
+        let (sender, receiver) = channel::<DecoderMessage>();
+        let (exit_sender, exited) = channel::<()>();
+        let thread = std::thread::Builder::new().name(self.thread_name.clone()).spawn({
+            let settings = self.settings;
+            let next_frame_rgb8 = self.next_frame_rgb8.clone();
+            let clears_handled = self.clears_handled.clone();
+            let counters = self.counters.clone();
+            let cancelled = self.cancelled.clone();
+            move || {
+                // Dropped however the thread exits, including panics
+                let _exit_sender = exit_sender;
+                let mut state = DecodeState::new(settings, next_frame_rgb8, clears_handled, counters, cancelled).expect("Failed to create decoder");
+                while let Ok(message) = receiver.recv() {
+                    if !state.handle(message) {
+                        return;
+                    }
+                }
+            }
+        })?;
+        *self.sender.lock().expect("Could not get lock on sender") = Some(sender);
+        self.thread = Some(thread);
+        self.exited = Some(exited);
+        Ok(())
+    }
+
+    // Stops the decode thread, waits for it to exit and frees the decoded frames
+    // The thread skips the packets still queued for it, but a decode call can not be interrupted,
+    // so a thread stuck on a pathological packet is abandoned after STOP_TIMEOUT instead of blocking forever
+    fn stop(&mut self) {
+        self.inline = None;
+        self.cancelled.store(true, Ordering::Release);
+        if let Some(sender) = self.sender.lock().expect("Could not get lock on sender").take() {
+            let _ = sender.send(DecoderMessage::Stop);
+        }
+        if let Some(thread) = self.thread.take() {
+            let exited = self.exited.take().map_or(Err(RecvTimeoutError::Disconnected), |exited| exited.recv_timeout(STOP_TIMEOUT));
+            if exited != Err(RecvTimeoutError::Timeout) {
+                let _ = thread.join();
+            } else {
+                warn!("Decode thread {} did not stop within {:?}, abandoning it", self.thread_name, STOP_TIMEOUT);
+            }
+        }
+        let mut queue = self.next_frame_rgb8.lock().unwrap();
+        queue.clear();
+        queue.shrink_to_fit();
+        // The new thread starts without pending clears
+        self.clears_sent = 0;
+        self.clears_handled.store(0, Ordering::Release);
+        self.counters.consecutive_errors.store(0, Ordering::Relaxed);
+    }
+
+    fn send_message(&self, message: DecoderMessage) {
+        if let Some(inline) = &self.inline {
+            inline.lock().expect("Could not get lock on decoder").handle(message);
+        } else if let Some(sender) = self.sender.lock().expect("Could not get lock on sender").as_ref() {
+            sender.send(message).expect("Could not send packet to decoder");
+        }
+    }
+
+    // Returns the consecutive decode errors and starts counting from 0 again
+    fn take_consecutive_errors(&self) -> usize {
+        self.counters.consecutive_errors.swap(0, Ordering::Relaxed)
+    }
+
+    fn consecutive_errors(&self) -> usize {
+        self.counters.consecutive_errors.load(Ordering::Relaxed)
+    }
+
+    fn update_throughput(&mut self, now: f64) {
+        let pictures = self.counters.pictures.load(Ordering::Relaxed);
+        let bytes = self.counters.bytes.load(Ordering::Relaxed);
+        self.throughput.update(now, pictures, bytes);
+    }
+
+    fn send(&self, video_packet: Vec<u8>) {
+        self.send_at(video_packet, None);
+    }
+
+    fn send_at(&self, video_packet: Vec<u8>, position: Option<FramePosition>) {
+        self.send_message(DecoderMessage::Frame(video_packet, position, Instant::now()));
+    }
+
+    fn send_alpha(&self, alpha_packet: Vec<u8>) {
+        self.send_message(DecoderMessage::Alpha(alpha_packet));
+    }
+
+    fn take_frame(&self) -> Option<VideoFrame> {
+        if let Ok(mut queue) = self.next_frame_rgb8.lock() {
+            if self.is_stale() {
+                return None;
+            }
+            queue.pop_front()
+        } else {
+            None
+        }
+    }
+
+    // Must be called with the queue locked
+    fn is_stale(&self) -> bool {
+        self.clears_handled.load(Ordering::Acquire) != self.clears_sent
+    }
+
+    // Drops everything but the most recently decoded frame
+    fn take_latest_frame(&self) -> Option<VideoFrame> {
+        if let Ok(mut queue) = self.next_frame_rgb8.lock() {
+            if self.is_stale() {
+                return None;
+            }
+            let latest = queue.pop_back();
+            queue.clear();
+            latest
+        } else {
+            None
+        }
+    }
+
+    fn queued_frames(&self) -> usize {
+        let queue = self.next_frame_rgb8.lock().unwrap();
+        if self.is_stale() {
+            0
+        } else {
+            queue.len()
+        }
+    }
+
+    fn clear_frames(&self) {
+        self.next_frame_rgb8.lock().unwrap().clear();
+    }
+
+    // Unlike clear_frames this also drops the frames of packets that are still waiting to be decoded
+    fn clear_pending_frames(&mut self) {
+        if !self.is_running() {
+            self.clear_frames();
+            return;
+        }
+        self.clears_sent += 1;
+        self.send_message(DecoderMessage::ClearFrames);
+    }
+}
+
+impl Drop for DecodeWorker {
+    // Unlike stop this does not wait for the thread
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Release);
+        if let Some(sender) = self.sender.lock().expect("Could not get lock on sender").as_ref() {
+            sender.send(DecoderMessage::Stop).expect("Could not send end packet to decoder");
+        }
+    }
+}
+
+const PLACEHOLDER_SIZE: Extent3d = Extent3d {
+    width: 12,
+    height: 12,
+    depth_or_array_layers: 1,
+};
+
+// usages are added to the default texture usages of an Image, see H264DecoderBuilder::texture_usages
+fn create_render_target(images: &mut Assets<Image>, size: Extent3d, format: TextureFormat, usages: TextureUsages, fill: &[u8]) -> Handle<Image> {
+    let mut image = Image::new_fill(
+        size,
+        TextureDimension::D2,
+        fill,
+        format,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    image.texture_descriptor.usage |= usages;
+    images.add(image)
+}
+
+// Malformed or non picture output can come out of the decoder as a 0x0 picture
+fn has_zero_size(decoded_yuv: &DecodedYUV) -> bool {
+    let (width, height) = decoded_yuv.dimension_rgb();
+    width == 0 || height == 0
+}
+
+// Copies a decoded frame into the render target, resizing it if the video dimensions changed
+// Frames without pixels are skipped since the render target can not be resized to them, returns false in that case
+fn upload_frame(image: &mut Image, frame: VideoFrame) -> bool {
+    if frame.width == 0 || frame.height == 0 {
+        warn_once!("Skipping upload of a frame without pixels");
+        return false;
+    }
+    if image.texture_descriptor.size.width != frame.width as u32 || image.texture_descriptor.size.height != frame.height as u32 {
+        image.resize(Extent3d { width: frame.width as u32, height: frame.height as u32, depth_or_array_layers: 1 });
+    }
+    image.data = frame.buffer;
+    true
+}
+
+// Copies the render target into an RGBA image for the image crate, e.g. to save a screenshot or thumbnail
+// Float render targets are converted back to 8 bit sRGB
+#[cfg(feature = "image")]
+fn render_target_to_rgba(images: &Assets<Image>, render_target: &Handle<Image>) -> Option<image::RgbaImage> {
+    let image = images.get(render_target)?;
+    let size = image.texture_descriptor.size;
+    let buffer = match image.texture_descriptor.format {
+        TextureFormat::Rgba16Float => image.data.chunks_exact(8).flat_map(|pixel| {
+            let channel = |idx: usize| f16::from_le_bytes([pixel[idx * 2], pixel[idx * 2 + 1]]).to_f32();
+            bevy_color::LinearRgba::new(channel(0), channel(1), channel(2), channel(3)).to_srgba().to_u8_array()
+        }).collect(),
+        TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm => image.data.clone(),
+        _ => {
+            let mut buffer = image.data.clone();
+            for pixel in buffer.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+            buffer
+        }
+    };
+    image::RgbaImage::from_raw(size.width, size.height, buffer)
+}
+
+// The NAL unit type of an Annex B NAL unit, with or without its start code
+fn nal_type(nal: &[u8]) -> Option<u8> {
+    let start = nal.iter().position(|byte| *byte != 0)?;
+    let header = if nal[start] == 1 && start >= 2 { nal.get(start + 1)? } else { &nal[start] };
+    Some(header & 0x1F)
+}
+
+// Index of the first keyframe, including the parameter sets and SEI directly in front of it
+// Matches H264Video::loop_start, but works on a partially loaded video
+fn first_keyframe_start(buffer: &[Vec<u8>]) -> Option<usize> {
+    let keyframe = buffer.iter().position(|nal| nal_type(nal) == Some(NAL_IDR))?;
+    let start = buffer[..keyframe].iter().rposition(|nal| nal_type(nal).is_some_and(is_picture_nal)).map_or(0, |idx| idx + 1);
+    Some(start)
+}
+
+// Coded slices of a picture, as opposed to parameter sets, SEI and other metadata
+fn is_picture_nal(nal_type: u8) -> bool {
+    (1..=NAL_IDR).contains(&nal_type)
+}
+
+// The bytes following the NAL header, with or without a start code in front of it
+fn nal_payload(nal: &[u8]) -> Option<&[u8]> {
+    let start = nal.iter().position(|byte| *byte != 0)?;
+    let header = if nal[start] == 1 && start >= 2 { start + 1 } else { start };
+    nal.get(header + 1..)
+}
+
+// Whether a slice NAL starts a new picture, i.e. its first_mb_in_slice is 0
+// first_mb_in_slice is the first exp-golomb value after the NAL header and 0 is coded as a single 1 bit
+fn is_first_slice(nal: &[u8]) -> bool {
+    nal_payload(nal).and_then(|payload| payload.first()).is_some_and(|byte| byte & 0x80 != 0)
+}
+
+// The payload of a NAL unit with the emulation prevention bytes removed, 00 00 03 is an escaped 00 00
+fn nal_rbsp(nal: &[u8]) -> Option<Vec<u8>> {
+    nal_rbsp_prefix(nal, usize::MAX)
+}
+
+// Bytes the header fields read by split_tracks fit in, even with a few emulation prevention bytes among them
+const RBSP_HEADER_LEN: usize = 32;
+
+// Like nal_rbsp, but stops after len bytes, so reading a slice header does not copy the whole slice
+fn nal_rbsp_prefix(nal: &[u8], len: usize) -> Option<Vec<u8>> {
+    let payload = nal_payload(nal)?;
+    let mut rbsp = Vec::with_capacity(payload.len().min(len));
+    let mut zeros = 0;
+    for &byte in payload {
+        if rbsp.len() >= len {
+            break;
+        }
+        if byte == 3 && zeros >= 2 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    Some(rbsp)
+}
+
+// Reads the exp-golomb coded fields at the start of parameter sets and slice headers
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        let byte = self.data.get(self.pos / 8)?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Some(bit as u32)
+    }
+
+    fn skip_bits(&mut self, count: usize) {
+        self.pos += count;
+    }
+
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        let mut value = 0;
+        for _ in 0..leading_zeros {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Some((1 << leading_zeros) - 1 + value)
+    }
+}
+
+// Splits a file with several elementary streams, e.g. a two camera capture, into one NAL list per SPS id
+// Slices are matched to their SPS through the PPS they reference
+// Other NAL units such as SEI go with the next NAL unit that belongs to a track
+// Files with a single SPS id are returned unchanged
+fn split_tracks(buffer: Vec<Vec<u8>>) -> Vec<Vec<Vec<u8>>> {
+    // Track of every NAL unit, None until known
+    let mut assigned = Vec::with_capacity(buffer.len());
+    let mut sps_tracks: Vec<u32> = Vec::new();
+    let mut pps_to_sps = HashMap::new();
+    for nal in &buffer {
+        let rbsp = nal_rbsp_prefix(nal, RBSP_HEADER_LEN).unwrap_or_default();
+        let mut reader = BitReader::new(&rbsp);
+        let sps_id = match nal_type(nal) {
+            Some(NAL_SPS) => {
+                // profile_idc, constraint flags and level_idc come before the id
+                reader.skip_bits(24);
+                reader.read_ue()
+            }
+            Some(NAL_PPS) => reader.read_ue().zip(reader.read_ue()).map(|(pps_id, sps_id)| {
+                pps_to_sps.insert(pps_id, sps_id);
+                sps_id
+            }),
+            Some(nal_type) if is_picture_nal(nal_type) => {
+                // first_mb_in_slice and slice_type come before the PPS id
+                let pps_id = reader.read_ue().and(reader.read_ue()).and(reader.read_ue());
+                pps_id.and_then(|pps_id| pps_to_sps.get(&pps_id).copied())
+            }
+            _ => None,
+        };
+        let track = sps_id.map(|sps_id| match sps_tracks.iter().position(|id| *id == sps_id) {
+            Some(track) => track,
+            None => {
+                sps_tracks.push(sps_id);
+                sps_tracks.len() - 1
+            }
+        });
+        assigned.push(track);
+    }
+    if sps_tracks.len() <= 1 {
+        return vec![buffer];
+    }
+
+    let mut tracks = vec![Vec::new(); sps_tracks.len()];
+    let mut pending = Vec::new();
+    let mut last_track = 0;
+    for (nal, track) in buffer.into_iter().zip(assigned) {
+        match track {
+            Some(track) => {
+                tracks[track].append(&mut pending);
+                tracks[track].push(nal);
+                last_track = track;
+            }
+            None => pending.push(nal),
+        }
+    }
+    tracks[last_track].append(&mut pending);
+    tracks
+}
+
+const SEI_USER_DATA_UNREGISTERED: usize = 5;
+
+// Extracts the user data unregistered messages of an SEI NAL
+fn parse_user_data_sei(nal: &[u8]) -> Vec<Sei> {
+    let Some(rbsp) = nal_rbsp(nal) else { return Vec::new() };
+
+    // Payload type and size are coded as a run of 0xFF bytes plus a final byte
+    fn read_value(rbsp: &[u8], pos: &mut usize) -> Option<usize> {
+        let mut value = 0;
+        loop {
+            let byte = *rbsp.get(*pos)?;
+            *pos += 1;
+            value += byte as usize;
+            if byte != 0xFF {
+                return Some(value);
+            }
+        }
+    }
+
+    let mut messages = Vec::new();
+    let mut pos = 0;
+    // Stop at the rbsp trailing bits
+    while pos < rbsp.len() && rbsp[pos] != 0x80 {
+        let Some(payload_type) = read_value(&rbsp, &mut pos) else { break };
+        let Some(payload_size) = read_value(&rbsp, &mut pos) else { break };
+        let Some(message) = rbsp.get(pos..pos + payload_size) else { break };
+        pos += payload_size;
+        if payload_type == SEI_USER_DATA_UNREGISTERED && message.len() >= 16 {
+            let (uuid, payload) = message.split_at(16);
+            messages.push(Sei {
+                uuid: uuid.try_into().unwrap(),
+                payload: payload.to_vec(),
+            });
+        }
+    }
+    messages
+}
+
+// Prefixes a NAL unit with an Annex B start code if it does not already have one
+fn with_start_code(nal: Vec<u8>) -> Vec<u8> {
+    if nal.starts_with(&[0, 0, 1]) || nal.starts_with(&[0, 0, 0, 1]) {
+        nal
+    } else {
+        [&[0, 0, 0, 1], nal.as_slice()].concat()
+    }
+}
+
+// What the render target shows when decode_video has no frame ready for a tick, e.g. when the decode thread stalls
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StallBehavior {
+    // Keep showing the last presented frame, or the transparent placeholder before the first frame
+    #[default]
+    HoldLast,
+    // Fill the render target with a solid color until frames arrive again
+    Fill(Color),
+}
+
+// A single pixel of the given color in the render target's format
+fn color_to_pixel(color: Color, format: TextureFormat) -> Vec<u8> {
+    match format {
+        TextureFormat::Rgba16Float => color.to_linear().to_f32_array().iter().flat_map(|channel| f16::from_f32(*channel).to_le_bytes()).collect(),
+        TextureFormat::Rgba8UnormSrgb | TextureFormat::Rgba8Unorm => color.to_srgba().to_u8_array().to_vec(),
+        _ => {
+            let [r, g, b, a] = color.to_srgba().to_u8_array();
+            vec![b, g, r, a]
+        }
+    }
+}
+
+// A fill color also covers the placeholder, so the surface is intentional before the first frame
+fn placeholder_fill(stall_behavior: StallBehavior, format: TextureFormat) -> Vec<u8> {
+    match stall_behavior {
+        StallBehavior::HoldLast => color_to_pixel(Color::NONE, format),
+        StallBehavior::Fill(color) => color_to_pixel(color, format),
+    }
+}
+
+// Which NAL units that do not affect the decoded pictures are still sent to the decoder
+// Everything else is always sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NalFilter {
+    // SEI carries metadata such as captions and timecodes, which the decoder does not need
+    pub forward_sei: bool,
+    // Filler data only pads the bitrate
+    pub forward_filler: bool,
+}
+
+impl NalFilter {
+    // Whether a NAL unit of the given type is sent to the decoder
+    pub fn forwards(&self, nal_type: u8) -> bool {
+        match nal_type {
+            NAL_SEI => self.forward_sei,
+            NAL_FILLER => self.forward_filler,
+            _ => true,
+        }
+    }
+
+    fn forwards_nal(&self, nal: &[u8]) -> bool {
+        nal_type(nal).is_none_or(|nal_type| self.forwards(nal_type))
+    }
+}
+
+// Who presents the decoded frames
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Presentation {
+    // decode_video uploads every frame to the render target
+    #[default]
+    RenderTarget,
+    // Frames are left in the queue for H264Decoder::try_take_frame and the render target is never written
+    // Playback only advances as frames are taken and does not pause at the end
+    Manual,
+}
+
+// What a decoder does when its render target was removed from Assets<Image>, e.g. by asset churn
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TargetLostPolicy {
+    // Create a new render target with the last known size and format and keep playing
+    #[default]
+    Recreate,
+    // Remove the H264Decoder component
+    Remove,
+}
+
+// What a decoder does while its entity is not visible, according to ViewVisibility
+// Entities without ViewVisibility always count as visible
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HiddenBehavior {
+    // Keep decoding and presenting as usual
+    #[default]
+    Play,
+    // Keep decoding so playback stays in time, but do not upload frames to the render target
+    SkipPresent,
+    // Stop decoding and presenting, resuming from the same frame once visible again
+    Pause,
+}
+
+// What the render target shows while the decoder is paused
+// This applies to any pause, including the one inserted at the end of a non repeating video
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PauseDisplay {
+    // Keep showing the last presented frame
+    #[default]
+    Hold,
+    // Blank the render target to transparent black
+    Clear,
+}
+
+// Playback position of a single decoder, counted in frames
+// Every fixed tick adds speed frames, and decode_video presents the whole frames that became due
+// Frames are counted per tick on purpose instead of accumulating Time deltas, so 1.0 stays exactly one frame per tick like before
+#[derive(Clone, Copy, Debug)]
+struct PlaybackClock {
+    speed: f64,
+    // Fraction of a frame carried over to the next tick
+    pending: f64,
+    // Where pending starts after a reset, see Pacing
+    offset: f64,
+}
+
+impl PlaybackClock {
+    fn new(speed: f64, pacing: Pacing) -> Self {
+        let offset = match pacing {
+            Pacing::FixedTick => 0.0,
+            // A frame becomes due half a frame before its presentation time, so the nearest one is shown
+            Pacing::NearestToDisplay => 0.5,
+        };
+        Self { speed, pending: offset, offset }
+    }
+
+    // Advances by the given number of frame durations at speed 1.0 and returns how many frames are due
+    // Frames that are due but not decoded yet are not caught up on later, the video stalls instead
+    fn tick(&mut self, frames: f64) -> usize {
+        self.pending += self.speed * frames;
+        // pending is negative after slowing down for audio sync
+        let due = self.pending.floor().max(0.0);
+        self.pending -= due;
+        due as usize
+    }
+
+    fn reset(&mut self) {
+        self.pending = self.offset;
+    }
+
+    // Moves playback forward or back by a fraction of a frame, without presenting anything
+    fn nudge(&mut self, frames: f64) {
+        self.pending += frames;
+    }
+}
+
+// Audio sync corrects this fraction of the drift every tick
+const AUDIO_SYNC_GAIN: f64 = 0.1;
+// At most half a frame per tick, so corrections do not visibly skip or repeat frames
+const AUDIO_SYNC_MAX_FRAMES: f64 = 0.5;
+const MAX_AUDIO_DRIFT_SECS: f64 = 1.0;
+
+// What a decoder does when packets keep failing to decode, e.g. because the file is corrupt or truncated
+// The count resets whenever a picture is decoded successfully
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    // Keep sending packets, the decoder recovers by itself at the next keyframe if it can
+    #[default]
+    Ignore,
+    // Pause the decoder with H264DecoderPause after this many consecutive errors and send H264DecodeErrorEvent
+    StopAfter(u32),
+    // Restart decoding from the keyframe at or before the current frame after this many consecutive errors
+    // and send H264DecodeErrorEvent
+    RestartAfter(u32),
+}
+
+// Sent when a decoder's ErrorPolicy stopped or restarted it
+#[derive(Event)]
+pub struct H264DecodeErrorEvent {
+    pub entity: Entity,
+    pub consecutive_errors: usize,
+    // The decoder was restarted rather than stopped
+    pub restarted: bool,
+}
+
+// When a presented frame is copied into the render target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UploadTiming {
+    // Right away in H264Systems::Present, so the frame and its H264UpdateEvent are available from Update on
+    #[default]
+    Immediate,
+    // Deferred to H264Systems::Upload in Last, right before render extraction
+    // When several fixed ticks run in one render frame only the latest frame is uploaded,
+    // and systems ordered after H264Systems::Upload are guaranteed to see the frame that gets rendered
+    Last,
+}
+
+// When decoded frames are presented
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    // One frame per FixedUpdate tick. The video plays at exactly the fixed rate,
+    // but ticks do not line up with the display's refresh, so e.g. 30fps on a 144Hz monitor is shown for an uneven number of refreshes
+    #[default]
+    FixedTick,
+    // Every rendered frame, in Update, the frame whose presentation time is nearest to the current time is shown
+    // Frame times are the picture index times the fixed timestep
+    // This smooths out judder on displays that are not a multiple of the video's rate,
+    // but follows the render frame rate, so hitches in the game skip video frames instead of delaying them
+    NearestToDisplay,
+}
+
+// Where a decoder is in its playback lifecycle
+// update_playback_phase is the one system that moves decoders between phases and handles the end of the video,
+// other code only requests a change by setting Ending or by leaving the end after a seek or restart
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaybackPhase {
+    // H264DecoderLoading is still on the entity
+    #[default]
+    Loading,
+    Playing,
+    // The last frame of a non repeating video was presented, resolved to Finished, or to Playing if repeat was turned on
+    Ending,
+    // H264DecoderPause is on the entity, inserted by the user or by the ErrorPolicy
+    Paused,
+    // Holding the last frame of a non repeating video, paused unless auto_pause_on_end is off
+    // Restarting, seeking or turning repeat on plays again
+    Finished,
+}
+
+// Sent whenever a decoder moves to another PlaybackPhase
+#[derive(Event, Clone, Copy, Debug)]
+pub struct H264PlaybackPhaseEvent {
+    pub entity: Entity,
+    pub previous: PlaybackPhase,
+    pub phase: PlaybackPhase,
+}
+
+// The companion video of H264DecoderBuilder::alpha_video, pushed picture by picture alongside the color video
+// The video itself stays in Assets<H264Video> and is looked up with the handle whenever packets are pushed
+struct AlphaStream {
+    handle: Handle<H264Video>,
+    // The picture the alpha decoder continues with, pushing any other one first resyncs it from a keyframe
+    next_picture: Option<usize>,
+}
+
+#[derive(Component)]
+pub struct H264Decoder {
+    video: Handle<H264Video>,
+    render_target: Handle<Image>,
+    // Mirrors the render target so it can be read without Assets<Image>
+    output_format: TextureFormat,
+    output_size: Extent3d,
+    // Kept so a recreated render target gets them too
+    texture_usages: TextureUsages,
+    repeat: bool,
+    phase: PlaybackPhase,
+    // The phase last sent with H264PlaybackPhaseEvent
+    reported_phase: PlaybackPhase,
+    auto_pause_on_end: bool,
+    pause_display: PauseDisplay,
+    stall_behavior: StallBehavior,
+    // The render target currently shows the stall fill
+    stalled: bool,
+    hidden_behavior: HiddenBehavior,
+    target_lost_policy: TargetLostPolicy,
+    error_policy: ErrorPolicy,
+    nal_filter: NalFilter,
+    presentation: Presentation,
+    clock: PlaybackClock,
+    pacing: Pacing,
+    // Duration of a frame at speed 1.0, the fixed timestep as of the last tick
+    frame_secs: f64,
+    // Set by set_observed_audio_time until the next tick uses it
+    observed_audio_secs: Option<f64>,
+    audio_drift: Option<f64>,
+    upload_timing: UploadTiming,
+    // The frame waiting for upload_pending, see UploadTiming::Last
+    pending_upload: Option<VideoFrame>,
+    keep_snapshot: bool,
+    // A copy of the last uploaded frame while keep_snapshot is on
+    snapshot: Option<VideoFrame>,
+    // Counts the changes to the render target, every time H264UpdateEvent is sent
+    generation: u64,
+    latency: LatencyWindow,
+    loop_crossfade_frames: u32,
+    // The crossfade length for the current video, 0 while it is unknown or disabled
+    crossfade_len: usize,
+    // The first crossfade_len frames of the video, blended over the last ones
+    loop_head: Vec<VideoFrame>,
+    
+    next_frame: usize,
+    frame_count: usize,
+    segment: Option<Range<usize>>,
+    // The frames playback loops between, the segment clamped to the video or the whole video
+    loop_first_frame: usize,
+    loop_end_frame: usize,
+    // Loop of next_frame, see FramePosition
+    loop_idx: usize,
+
+    frame_idx: usize,
+    // Position of the next picture push_packet sends
+    push_position: FramePosition,
+    loop_start: usize,
+    // Frame 0 was already presented from the video's first frame cache
+    // so the decode thread's copy of it only advances playback
+    first_frame_presented: bool,
+
+    // The whole video was sent at once because it is degenerate, push_packet has nothing left to send
+    one_shot: bool,
+    // Set by set_video_at until the new video is loaded
+    pending_video: Option<(Handle<H264Video>, f64)>,
+    // Frame push_packet seeks to before pushing anything else, set by start
+    seek_pending: Option<usize>,
+    alpha: Option<AlphaStream>,
+    // The partially loaded video while playing progressively, see H264VideoLoaderSettings::progressive
+    // frame_count is unknown until the load finishes
+    progressive: Option<ProgressiveBuffer>,
+
+    // push_packet only refills once fewer than low_water_mark frames are queued, and then fills up to high_water_mark
+    low_water_mark: usize,
+    high_water_mark: usize,
+
+    worker: DecodeWorker,
+}
+
+// Configures a decoder before it is created
+// H264Decoder::new is a shorthand for the default builder with repeat set
+pub struct H264DecoderBuilder {
+    video: Handle<H264Video>,
+    repeat: bool,
+    auto_pause_on_end: bool,
+    pause_display: PauseDisplay,
+    thread_name: Option<String>,
+    water_marks: (f32, f32),
+    convert_options: ConvertOptions,
+    stall_behavior: StallBehavior,
+    hidden_behavior: HiddenBehavior,
+    target_lost_policy: TargetLostPolicy,
+    error_policy: ErrorPolicy,
+    nal_filter: NalFilter,
+    output_format: OutputFormat,
+    presentation: Presentation,
+    speed: f64,
+    pacing: Pacing,
+    upload_timing: UploadTiming,
+    loop_crossfade_frames: u32,
+    segment: Option<Range<usize>>,
+    keep_snapshot: bool,
+    decode_mode: DecodeMode,
+    alpha_video: Option<Handle<H264Video>>,
+    texture_usages: TextureUsages,
+}
+
+impl H264DecoderBuilder {
+    pub fn new(video: Handle<H264Video>) -> Self {
+        Self {
+            video,
+            repeat: false,
+            auto_pause_on_end: true,
+            pause_display: PauseDisplay::default(),
+            thread_name: None,
+            water_marks: (1.0, 1.0),
+            convert_options: ConvertOptions::default(),
+            stall_behavior: StallBehavior::default(),
+            hidden_behavior: HiddenBehavior::default(),
+            target_lost_policy: TargetLostPolicy::default(),
+            error_policy: ErrorPolicy::default(),
+            nal_filter: NalFilter::default(),
+            output_format: OutputFormat::default(),
+            presentation: Presentation::default(),
+            speed: 1.0,
+            pacing: Pacing::default(),
+            upload_timing: UploadTiming::default(),
+            loop_crossfade_frames: 0,
+            segment: None,
+            keep_snapshot: false,
+            decode_mode: DecodeMode::default(),
+            alpha_video: None,
+            texture_usages: TextureUsages::empty(),
+        }
+    }
+
+    pub fn repeat(mut self, repeat: bool) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    // Whether H264DecoderPause is inserted at the end of a non repeating video. Defaults to true
+    // When off the decoder holds the last frame without pausing, until it is restarted, seeked or repeat is turned on
+    pub fn auto_pause_on_end(mut self, auto_pause_on_end: bool) -> Self {
+        self.auto_pause_on_end = auto_pause_on_end;
+        self
+    }
+
+    pub fn pause_display(mut self, pause_display: PauseDisplay) -> Self {
+        self.pause_display = pause_display;
+        self
+    }
+
+    // Refill hysteresis of the decoded frame buffer, as fractions of the buffer size
+    // Packets are only pushed once the buffer drops below low, and then until it reaches high
+    // A lower low mark gives the decode thread longer idle stretches at the cost of less headroom against stalls
+    // Defaults to (1.0, 1.0), topping up the buffer every tick
+    pub fn refill_water_marks(mut self, low: f32, high: f32) -> Self {
+        let high = high.clamp(0.0, 1.0);
+        self.water_marks = (low.clamp(0.0, high), high);
+        self
+    }
+
+    pub fn stall_behavior(mut self, stall_behavior: StallBehavior) -> Self {
+        self.stall_behavior = stall_behavior;
+        self
+    }
+
+    // Saves decoding and uploads for videos that are off screen, see HiddenBehavior
+    pub fn hidden_behavior(mut self, hidden_behavior: HiddenBehavior) -> Self {
+        self.hidden_behavior = hidden_behavior;
+        self
+    }
+
+    pub fn target_lost_policy(mut self, target_lost_policy: TargetLostPolicy) -> Self {
+        self.target_lost_policy = target_lost_policy;
+        self
+    }
+
+    pub fn error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    // Defaults to dropping both SEI and filler data
+    pub fn nal_filter(mut self, nal_filter: NalFilter) -> Self {
+        self.nal_filter = nal_filter;
+        self
+    }
+
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.output_format = output_format;
+        self
+    }
+
+    pub fn presentation(mut self, presentation: Presentation) -> Self {
+        self.presentation = presentation;
+        self
+    }
+
+    // See H264Decoder::set_speed
+    pub fn speed(mut self, speed: f64) -> Self {
+        self.speed = speed.max(0.0);
+        self
+    }
+
+    pub fn pacing(mut self, pacing: Pacing) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    pub fn upload_timing(mut self, upload_timing: UploadTiming) -> Self {
+        self.upload_timing = upload_timing;
+        self
+    }
+
+    // Blends the last frames of a repeating video with its first frames instead of cutting at the loop, for ambient videos
+    // The first frames are kept in memory from the first loop, later loops continue right after them,
+    // so each loop is this many frames shorter. Later loops decode from the keyframe at or before the end of the head and drop the frames before it
+    // Only applies to presentation by decode_video, and to videos at least twice as long. Defaults to 0, a hard cut
+    pub fn loop_crossfade_frames(mut self, loop_crossfade_frames: u32) -> Self {
+        self.loop_crossfade_frames = loop_crossfade_frames;
+        self
+    }
+
+    // Keeps a copy of every presented frame for H264Decoder::snapshot
+    // This clones the whole pixel buffer on every upload, so it is off by default
+    pub fn keep_snapshot(mut self, keep_snapshot: bool) -> Self {
+        self.keep_snapshot = keep_snapshot;
+        self
+    }
+
+    // Outputs RGBA8 sRGB for use in a bevy_ui ImageNode
+    // The default BGRA target shows red and blue swapped in UI on some backends, this avoids the swizzle entirely
+    pub fn for_ui(self) -> Self {
+        self.output_format(OutputFormat::Rgba8UnormSrgb)
+    }
+
+    // Usages added to the render target's texture, e.g. TextureUsages::STORAGE_BINDING to use it in compute passes
+    // Storage textures can not be sRGB, so pair that with OutputFormat::Rgba8Unorm or OutputFormat::Rgba16Float
+    pub fn texture_usages(mut self, texture_usages: TextureUsages) -> Self {
+        self.texture_usages = texture_usages;
+        self
+    }
+
+    // Outputs RGBA8 without the sRGB view and adds STORAGE_BINDING, so compute shaders can read and write the frames
+    pub fn for_compute(self) -> Self {
+        self.output_format(OutputFormat::Rgba8Unorm).texture_usages(TextureUsages::STORAGE_BINDING)
+    }
+
+    // Plays only the frames in the range, e.g. one chapter of a longer video, and ends or loops at its end
+    // The start is moved back to the keyframe at or before it, since decoding can only start there
+    // Seeks are clamped to the segment. Disables loop_crossfade_frames
+    pub fn segment(mut self, segment: Range<usize>) -> Self {
+        self.segment = Some(segment);
+        self
+    }
+
+    pub fn chroma_upsampling(mut self, chroma_upsampling: ChromaUpsampling) -> Self {
+        self.convert_options.chroma_upsampling = chroma_upsampling;
+        self
+    }
+
+    // Replaces all conversion options, including chroma_upsampling
+    pub fn convert_options(mut self, convert_options: ConvertOptions) -> Self {
+        self.convert_options = convert_options;
+        self
+    }
+
+    // A second video whose luma becomes the alpha channel of the output, for transparency without an alpha capable codec
+    // Both are decoded in lockstep, so they must have the same frame count, frame rate and resolution
+    // Playback waits until both are loaded. If the alpha video fails to load or its frame count differs, the video plays opaque
+    pub fn alpha_video(mut self, alpha_video: Handle<H264Video>) -> Self {
+        self.alpha_video = Some(alpha_video);
+        self
+    }
+
+    // Use DecodeMode::Sync to decode tiny videos without a thread of their own
+    pub fn decode_mode(mut self, decode_mode: DecodeMode) -> Self {
+        self.decode_mode = decode_mode;
+        self
+    }
+
+    // Names the decode thread as shown in profilers. Defaults to "h264 " followed by the video's asset path
+    pub fn thread_name(mut self, thread_name: impl Into<String>) -> Self {
+        self.thread_name = Some(thread_name.into());
+        self
+    }
+
+    // Panics if the decode thread can not be spawned, see try_build
+    pub fn build(self, images: &mut Assets<Image>) -> H264Decoder {
+        self.try_build(images).expect("Failed to spawn decode thread")
+    }
+
+    pub fn try_build(self, images: &mut Assets<Image>) -> std::io::Result<H264Decoder> {
+        let thread_name = self.thread_name.unwrap_or_else(|| match self.video.path() {
+            Some(path) => format!("h264 {path}"),
+            None => "h264 decode".to_string(),
+        });
+        let worker = DecodeWorker::spawn(thread_name, ConvertSettings {
+            options: self.convert_options,
+            output_format: self.output_format,
+            alpha: self.alpha_video.is_some(),
+        }, self.decode_mode)?;
+        let output_size = PLACEHOLDER_SIZE;
+        let output_format = self.output_format.texture_format();
+        if self.texture_usages.contains(TextureUsages::STORAGE_BINDING) && output_format.is_srgb() {
+            warn!("{:?} can not be bound as a storage texture, use OutputFormat::Rgba8Unorm or OutputFormat::Rgba16Float", output_format);
+        }
+        let render_target = create_render_target(images, output_size, output_format, self.texture_usages, &placeholder_fill(self.stall_behavior, output_format));
+        // Always keep at least one frame buffered
+        let high_water_mark = ((self.water_marks.1 * BUF_SIZE as f32).round() as usize).max(1);
+        let low_water_mark = ((self.water_marks.0 * BUF_SIZE as f32).round() as usize).clamp(1, high_water_mark);
+        Ok(H264Decoder {
+            video: self.video,
+            render_target: render_target.clone(),
+            output_format,
+            output_size,
+            texture_usages: self.texture_usages,
+            repeat: self.repeat,
+            phase: PlaybackPhase::Loading,
+            reported_phase: PlaybackPhase::Loading,
+            auto_pause_on_end: self.auto_pause_on_end,
+            pause_display: self.pause_display,
+            stall_behavior: self.stall_behavior,
+            // The placeholder already shows the fill
+            stalled: matches!(self.stall_behavior, StallBehavior::Fill(_)),
+            hidden_behavior: self.hidden_behavior,
+            target_lost_policy: self.target_lost_policy,
+            error_policy: self.error_policy,
+            nal_filter: self.nal_filter,
+            presentation: self.presentation,
+            clock: PlaybackClock::new(self.speed, self.pacing),
+            pacing: self.pacing,
+            frame_secs: Time::<Fixed>::default().timestep().as_secs_f64(),
+            observed_audio_secs: None,
+            audio_drift: None,
+            upload_timing: self.upload_timing,
+            pending_upload: None,
+            keep_snapshot: self.keep_snapshot,
+            snapshot: None,
+            generation: 0,
+            latency: LatencyWindow::default(),
+            loop_crossfade_frames: self.loop_crossfade_frames,
+            crossfade_len: 0,
+            loop_head: Vec::new(),
+            next_frame: 0,
+            frame_count: 0,
+            segment: self.segment,
+            loop_first_frame: 0,
+            loop_end_frame: 0,
+            loop_idx: 0,
+            frame_idx: 0,
+            push_position: FramePosition { loop_idx: 0, picture: 0 },
+            loop_start: 0,
+            first_frame_presented: false,
+            one_shot: false,
+            pending_video: None,
+            seek_pending: None,
+            alpha: self.alpha_video.map(|handle| AlphaStream { handle, next_picture: None }),
+            progressive: None,
+            low_water_mark,
+            high_water_mark,
+            worker,
+        })
+    }
+}
+
+impl H264Decoder {
+    pub fn new(images: &mut ResMut<Assets<Image>>, video: Handle<H264Video>, repeat: bool) -> Self {
+        Self::builder(video).repeat(repeat).build(images)
+    }
+
+    pub fn builder(video: Handle<H264Video>) -> H264DecoderBuilder {
+        H264DecoderBuilder::new(video)
+    }
+
+    pub fn repeat(&self) -> bool {
+        self.repeat
+    }
+
+    // Takes effect at the end of the current loop
+    // Turning repeat on after a non repeating video finished resumes it from the start,
+    // pauses inserted by anything else are left alone
+    pub fn set_repeat(&mut self, repeat: bool) {
+        self.repeat = repeat;
+    }
+
+    pub fn phase(&self) -> PlaybackPhase {
+        self.phase
+    }
+
+    // Seeking and restarting play a finished video again, update_playback_phase then settles on Playing or Paused
+    fn leave_end(&mut self) {
+        if matches!(self.phase, PlaybackPhase::Ending | PlaybackPhase::Finished) {
+            self.phase = PlaybackPhase::Playing;
+        }
+    }
+
+    pub fn pause_display(&self) -> PauseDisplay {
+        self.pause_display
+    }
+
+    // Takes effect the next time the decoder is paused
+    pub fn set_pause_display(&mut self, pause_display: PauseDisplay) {
+        self.pause_display = pause_display;
+    }
+
+    pub fn get_render_target(&self) -> Handle<Image> {
+        self.render_target.clone()
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.clock.speed
+    }
+
+    pub fn segment(&self) -> Option<Range<usize>> {
+        self.segment.clone()
+    }
+
+    // Increases every time the render target changes, the same times H264UpdateEvent is sent
+    // Remember it and compare with has_new_frame_since to poll for new frames instead of reading events
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn has_new_frame_since(&self, last: u64) -> bool {
+        self.generation > last
+    }
+
+    // Average time in milliseconds from sending a packet to the decode thread until its frame is presented, over the last 30 frames
+    // Includes decoding, conversion and the time spent in the queue, so a high value with a full queue means the buffer is larger than needed
+    // None until a frame has been presented
+    pub fn latency_ms(&self) -> Option<f64> {
+        self.latency.average_ms()
+    }
+
+    // A copy of the most recently presented frame, e.g. for a photo mode, without reading back the render target
+    // Clones the frame's BGRA (or RGBA16F) buffer. Requires H264DecoderBuilder::keep_snapshot, returns None otherwise
+    pub fn snapshot(&self) -> Option<VideoFrame> {
+        self.snapshot.clone()
+    }
+
+    // The playback time other media should follow, in seconds since the start of the current loop
+    // This is the start of the frame that is presented next, the same as H264PlaybackState::secs
+    pub fn master_time(&self) -> f64 {
+        self.next_frame as f64 * self.frame_secs
+    }
+
+    // Reports where an audio track that plays along with the video actually is, in seconds since its start
+    // Call it every tick. The video speeds up or slows down slightly until it matches,
+    // offsets of more than a second are ignored and should be fixed by seeking instead
+    pub fn set_observed_audio_time(&mut self, secs: f64) {
+        self.observed_audio_secs = Some(secs);
+    }
+
+    // Audio time minus master time, as of the last observed audio time
+    pub fn audio_drift(&self) -> Option<f64> {
+        self.audio_drift
+    }
+
+    // Playback speed relative to one frame per fixed tick, e.g. 0.5 for slow motion or 2.0 to play twice as fast
+    // Above 1.0 frames in between are decoded but skipped, so the decode thread has to keep up with the faster rate
+    // 0.0 holds the current frame without pausing. Negative speeds are clamped to 0.0
+    pub fn set_speed(&mut self, speed: f64) {
+        self.clock.speed = speed.max(0.0);
+    }
+
+    // The texture format of the render target
+    pub fn output_format(&self) -> TextureFormat {
+        self.output_format
+    }
+
+    // The current size of the render target
+    // Starts as a 12x12 placeholder and changes to the video's dimensions once the first frame is presented
+    pub fn output_size(&self) -> Extent3d {
+        self.output_size
+    }
+
+    // The currently presented frame as RGBA
+    #[cfg(feature = "image")]
+    pub fn current_frame_rgba(&self, images: &Assets<Image>) -> Option<image::RgbaImage> {
+        render_target_to_rgba(images, &self.render_target)
+    }
+
+    // Switches to another video, such as a different resolution encoding of the same content, once it has loaded
+    // Playback continues from the last keyframe at or before the given time in the new video
+    // The current video keeps playing until the switch happens
+    pub fn set_video_at(&mut self, video: Handle<H264Video>, seconds: f64) {
+        self.pending_video = Some((video, seconds));
+    }
+
+    // Restarts decoding from the last keyframe at or before the given frame
+    // Frames already decoded or waiting to be decoded are dropped
+    // The frame is clamped to the segment, if there is one
+    fn seek_to_keyframe(&mut self, video: &H264Video, alpha_video: Option<&H264Video>, frame: usize) {
+        let frame = frame.clamp(self.loop_first_frame, self.loop_end_frame.saturating_sub(1).max(self.loop_first_frame));
+        let keyframe = video.keyframe_at_or_before(frame);
+        if video.resolve_frame(keyframe).is_none() {
+            return;
+        }
+        self.worker.clear_pending_frames();
+        self.start_loop(keyframe);
+        self.push_from_keyframe(video, alpha_video, keyframe);
+        self.next_frame = keyframe;
+        self.clock.reset();
+        self.pending_upload = None;
+        self.leave_end();
+        self.first_frame_presented = false;
+    }
+
+    // Uploads a frame to the render target, returns false if nothing was uploaded
+    fn upload(&mut self, images: &mut Assets<Image>, frame: VideoFrame) -> bool {
+        let Some(image) = images.get_mut(&self.render_target) else { return false };
+        let snapshot = self.keep_snapshot.then(|| frame.clone());
+        self.latency.record(&frame);
+        if !upload_frame(image, frame) {
+            return false;
+        }
+        if snapshot.is_some() {
+            self.snapshot = snapshot;
+        }
+        self.generation += 1;
+        self.output_size = image.texture_descriptor.size;
+        self.output_format = image.texture_descriptor.format;
+        self.stalled = false;
+        true
+    }
+
+    // Handles a render target that was removed from Assets<Image> according to the TargetLostPolicy
+    // Returns false if the decoder has to be removed instead
+    fn ensure_render_target(&mut self, images: &mut Assets<Image>) -> bool {
+        if images.contains(&self.render_target) {
+            return true;
+        }
+        if self.target_lost_policy == TargetLostPolicy::Remove {
+            return false;
+        }
+        self.render_target = create_render_target(images, self.output_size, self.output_format, self.texture_usages, &placeholder_fill(self.stall_behavior, self.output_format));
+        self.stalled = matches!(self.stall_behavior, StallBehavior::Fill(_));
+        true
+    }
+
+    pub fn nal_filter(&self) -> NalFilter {
+        self.nal_filter
+    }
+
+    // Pushes packets until buffer_size reaches the high water mark
+    fn fill_buffer(&mut self, video: &H264Video, alpha_video: Option<&H264Video>, mut buffer_size: usize) {
+        // The first NAL after the loop, the end of the video unless a segment ends earlier
+        let end_idx = video.resolve_frame_end(self.loop_end_frame);
+        while buffer_size < self.high_water_mark {
+            // Checked before pushing, so a progressive load that finished with every NAL unit already pushed wraps too
+            if self.frame_idx >= end_idx {
+                // Wrap back to the first keyframe so the decoder is reset by an IDR and the next loop starts clean
+                self.frame_idx = video.loop_start;
+                self.push_position = FramePosition { loop_idx: self.push_position.loop_idx + 1, picture: 0 };
+                let resume = video.keyframe_at_or_before(self.crossfade_len);
+                if self.crossfade_ready() && resume > 0 {
+                    // The head was already shown blended over the tail, continue from the keyframe at or before its end
+                    // Head frames decoded again before crossfade_len are dropped by take_frame
+                    self.push_from_keyframe(video, alpha_video, resume);
+                } else if self.loop_first_frame > 0 {
+                    self.push_from_keyframe(video, alpha_video, self.loop_first_frame);
+                }
+            }
+            self.add_video_packet(video.buffer[self.frame_idx].clone(), alpha_video);
+            self.frame_idx += 1;
+            buffer_size += 1;
+        }
+    }
+
+    // Continues pushing packets from a keyframe picture, resending the parameter sets it can refer to
+    fn push_from_keyframe(&mut self, video: &H264Video, alpha_video: Option<&H264Video>, keyframe: usize) {
+        let Some(nal_idx) = video.resolve_frame(keyframe) else { return };
+        // The keyframe can refer to parameter sets from anywhere earlier in the stream
+        for &idx in video.parameter_sets.iter().take_while(|idx| **idx < nal_idx) {
+            self.add_video_packet(video.buffer[idx].clone(), alpha_video);
+        }
+        self.frame_idx = nal_idx;
+        self.push_position.picture = keyframe;
+    }
+
+    // Takes over the frame count, loop points and segment of a loaded or switched video
+    fn apply_video(&mut self, video: &H264Video) {
+        self.frame_count = video.frame_count();
+        self.loop_start = video.loop_start;
+        match &self.segment {
+            Some(segment) => {
+                // Decoding can only start at a keyframe
+                self.loop_first_frame = video.keyframe_at_or_before(segment.start.min(self.frame_count.saturating_sub(1)));
+                self.loop_end_frame = segment.end.clamp(self.loop_first_frame + 1, self.frame_count.max(1));
+                // The crossfade head is the start of the whole video
+                self.crossfade_len = 0;
+            }
+            None => {
+                self.loop_first_frame = 0;
+                self.loop_end_frame = self.frame_count;
+                self.crossfade_len = video.crossfade_len(self.loop_crossfade_frames as usize);
+            }
+        }
+    }
+
+    // Degenerate videos are sent whole, once
+    fn push_whole(&mut self, video: &H264Video, alpha_video: Option<&H264Video>) {
+        for nal in &video.buffer[video.loop_start..] {
+            self.add_video_packet(nal.clone(), alpha_video);
+        }
+    }
+
+    // Presents a degenerate video once instead of looping it, push_packet sends nothing for it afterwards
+    fn start_one_shot(&mut self, video: &H264Video, alpha_video: Option<&H264Video>) {
+        self.push_whole(video, alpha_video);
+        self.one_shot = true;
+        self.repeat = false;
+        if video.frame_count() == 0 {
+            // Nothing will ever be presented, so end right away
+            self.phase = PlaybackPhase::Ending;
+        }
+    }
+
+    fn add_video_packet(&mut self, video_packet: Vec<u8>, alpha_video: Option<&H264Video>) {
+        if !self.nal_filter.forwards_nal(&video_packet) {
+            return;
+        }
+        if nal_type(&video_packet).is_some_and(is_picture_nal) && is_first_slice(&video_packet) {
+            self.push_alpha(alpha_video, self.push_position.picture);
+            self.worker.send_at(video_packet, Some(self.push_position));
+            self.push_position.picture += 1;
+        } else {
+            self.worker.send(video_packet);
+        }
+    }
+
+    // Sends the alpha video's packets of a picture ahead of the color picture
+    // After a jump, e.g. a loop or seek, the alpha decoder first catches up from the keyframe at or before the picture
+    fn push_alpha(&mut self, alpha_video: Option<&H264Video>, picture: usize) {
+        let (Some(alpha), Some(video)) = (&mut self.alpha, alpha_video) else { return };
+        let Some(mut start) = video.resolve_frame(picture) else { return };
+        let end = video.resolve_frame_end(picture + 1);
+        let mut packets = Vec::new();
+        if alpha.next_picture != Some(picture) {
+            start = video.resolve_frame(video.keyframe_at_or_before(picture)).unwrap_or(start);
+            packets.extend(video.parameter_sets.iter().take_while(|idx| **idx < start).map(|&idx| video.buffer[idx].clone()));
+        }
+        packets.extend(video.buffer[start..end].iter().cloned());
+        alpha.next_picture = Some(picture + 1);
+        for packet in packets {
+            if self.nal_filter.forwards_nal(&packet) {
+                self.worker.send_alpha(packet);
+            }
+        }
+    }
+
+    // The alpha video to push alongside the color video, None without one or while it is still loading
+    fn alpha_video<'a>(&self, videos: &'a Assets<H264Video>) -> Option<&'a H264Video> {
+        self.alpha.as_ref().and_then(|alpha| videos.get(&alpha.handle))
+    }
+
+    // Returns false while the alpha video is still loading
+    // An alpha video that failed to load is dropped and the video plays opaque
+    fn load_alpha(&mut self, asset_server: &AssetServer, videos: &Assets<H264Video>) -> bool {
+        let Some(alpha) = &self.alpha else { return true };
+        if videos.contains(&alpha.handle) {
+            return true;
+        }
+        if let Some(LoadState::Failed(err)) = asset_server.get_load_state(&alpha.handle) {
+            warn!("Could not load the alpha video, playing without alpha: {err}");
+            self.alpha = None;
+            return true;
+        }
+        false
+    }
+
+    // Starts a new loop at the given picture for both presentation and push_packet
+    // Frames that are still queued from before belong to an older loop and are dropped by take_frame
+    fn start_loop(&mut self, picture: usize) {
+        self.loop_idx = self.push_position.loop_idx.max(self.loop_idx) + 1;
+        self.push_position = FramePosition { loop_idx: self.loop_idx, picture };
+    }
+
+    // Takes the frame for the current playback position
+    // Frames from before it are stale, e.g. from an earlier loop after a stall, and dropped
+    // If the decode thread skipped pictures, playback jumps ahead to the frame that came out instead
+    fn take_frame(&mut self) -> Option<VideoFrame> {
+        loop {
+            let frame = self.worker.take_frame()?;
+            let Some(position) = frame.position else { return Some(frame) };
+            if position < (FramePosition { loop_idx: self.loop_idx, picture: self.next_frame }) {
+                continue;
+            }
+            self.loop_idx = position.loop_idx;
+            self.next_frame = position.picture;
+            return Some(frame);
+        }
+    }
+
+    // Advances past the frame that was just taken, wrapping into the next loop at the end
+    // Returns true when the end of the video was reached
+    fn advance_frame(&mut self) -> bool {
+        self.next_frame += 1;
+        // The end is unknown while still loading progressively
+        if self.progressive.is_none() && self.next_frame >= self.loop_end_frame {
+            // With a crossfade the head of the next loop was already shown, frames before it are dropped if they were decoded anyway
+            self.next_frame = if self.crossfade_ready() { self.crossfade_len } else { self.loop_first_frame };
+            self.loop_idx += 1;
+            return true;
+        }
+        false
+    }
+
+    fn crossfade_ready(&self) -> bool {
+        self.repeat && self.crossfade_len > 0 && self.loop_head.len() == self.crossfade_len
+    }
+
+    // Keeps the first frames of the video and blends them over the last ones, see H264DecoderBuilder::loop_crossfade_frames
+    fn crossfade(&mut self, mut frame: VideoFrame) -> VideoFrame {
+        let len = self.crossfade_len;
+        if len == 0 {
+            return frame;
+        }
+        if self.next_frame < len {
+            if self.loop_head.len() == self.next_frame {
+                self.loop_head.push(frame.clone());
+            }
+        } else if self.crossfade_ready() {
+            if let Some(idx) = self.next_frame.checked_sub(self.frame_count - len) {
+                blend_frames(&mut frame, &self.loop_head[idx], (idx + 1) as f32 / (len + 1) as f32);
+            }
+        }
+        frame
+    }
+
+    // Takes the next decoded frame off the queue to present it yourself, advancing playback by one frame
+    // Build the decoder with Presentation::Manual, otherwise decode_video takes the frames first
+    pub fn try_take_frame(&mut self) -> Option<VideoFrame> {
+        let frame = self.take_frame()?;
+        self.latency.record(&frame);
+        self.advance_frame();
+        Some(frame)
+    }
+
+    // The number of decoded frames waiting to be presented
+    pub fn queued_frames(&self) -> usize {
+        self.worker.queued_frames()
+    }
+
+    // Stops the decode thread and frees its decoded frames, e.g. for a video that is not played again for a while
+    // The component, its settings and the render target with the last frame stay as they are until start is called
+    // Waits at most a second for the thread to finish the packet it is decoding, a thread stuck on a broken packet is abandoned
+    pub fn stop(&mut self) {
+        self.worker.stop();
+        self.pending_upload = None;
+        self.loop_head.clear();
+    }
+
+    // Spawns the decode thread again after stop, and plays from the start of the video or segment
+    pub fn start(&mut self) -> std::io::Result<()> {
+        if self.worker.is_running() {
+            return Ok(());
+        }
+        self.worker.start()?;
+        // The new alpha decoder has to start from a keyframe
+        if let Some(alpha) = &mut self.alpha {
+            alpha.next_picture = None;
+        }
+        self.leave_end();
+        self.seek_pending = Some(self.loop_first_frame);
+        Ok(())
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.worker.is_running()
+    }
+
+    // How fast the decode thread is going, e.g. to tell whether it keeps up with playback
+    pub fn throughput(&self) -> Throughput {
+        self.worker.throughput.throughput
+    }
+}
+
+// Decodes H264 that is pushed in at runtime rather than loaded as an H264Video, such as a live RTP/WebRTC feed
+// Each tick the most recently decoded frame is presented and older frames are dropped to keep latency low
+#[derive(Component)]
+pub struct H264StreamDecoder {
+    render_target: Handle<Image>,
+    output_format: TextureFormat,
+    output_size: Extent3d,
+
+    // Out of band SPS and PPS, resent ahead of every IDR frame
+    parameter_sets: Option<(Vec<u8>, Vec<u8>)>,
+    nal_filter: NalFilter,
+    latency: LatencyWindow,
+
+    worker: DecodeWorker,
+}
+
+impl H264StreamDecoder {
+    // Panics if the decode thread can not be spawned, see try_new
+    pub fn new(images: &mut Assets<Image>) -> Self {
+        Self::try_new(images, "h264 stream").expect("Failed to spawn decode thread")
+    }
+
+    // Names the decode thread as shown in profilers, e.g. after the peer or entity the stream belongs to
+    pub fn try_new(images: &mut Assets<Image>, thread_name: impl Into<String>) -> std::io::Result<Self> {
+        let worker = DecodeWorker::spawn(thread_name.into(), ConvertSettings::default(), DecodeMode::Threaded)?;
+        let output_size = PLACEHOLDER_SIZE;
+        let output_format = TextureFormat::Bgra8UnormSrgb;
+        Ok(Self {
+            render_target: create_render_target(images, output_size, output_format, TextureUsages::empty(), &[0, 0, 0, 0]),
+            output_format,
+            output_size,
+            parameter_sets: None,
+            nal_filter: NalFilter::default(),
+            latency: LatencyWindow::default(),
+            worker,
+        })
+    }
+
+    pub fn get_render_target(&self) -> Handle<Image> {
+        self.render_target.clone()
+    }
+
+    pub fn output_format(&self) -> TextureFormat {
+        self.output_format
+    }
+
+    pub fn output_size(&self) -> Extent3d {
+        self.output_size
+    }
+
+    #[cfg(feature = "image")]
+    pub fn current_frame_rgba(&self, images: &Assets<Image>) -> Option<image::RgbaImage> {
+        render_target_to_rgba(images, &self.render_target)
+    }
+
+    // How fast the decode thread is going, e.g. to tell whether it keeps up with the stream
+    pub fn throughput(&self) -> Throughput {
+        self.worker.throughput.throughput
+    }
+
+    // See H264Decoder::latency_ms
+    pub fn latency_ms(&self) -> Option<f64> {
+        self.latency.average_ms()
+    }
+
+    // Sets the SPS and PPS used for all following IDR frames, e.g. when they arrive through signaling
+    // Start codes are optional. The new sets are sent to the decoder immediately and again ahead of every IDR frame,
+    // so a resolution or parameter change mid stream takes effect at the next keyframe
+    pub fn set_parameter_sets(&mut self, sps: Vec<u8>, pps: Vec<u8>) {
+        let sps = with_start_code(sps);
+        let pps = with_start_code(pps);
+        self.worker.send(sps.clone());
+        self.worker.send(pps.clone());
+        self.parameter_sets = Some((sps, pps));
+    }
+
+    pub fn nal_filter(&self) -> NalFilter {
+        self.nal_filter
+    }
+
+    // Defaults to dropping both SEI and filler data
+    pub fn set_nal_filter(&mut self, nal_filter: NalFilter) {
+        self.nal_filter = nal_filter;
+    }
+
+    // Feeds a single NAL unit to the decoder. The start code is optional
+    // NAL units the NalFilter does not forward are dropped
+    pub fn push_nal_unit(&self, nal: Vec<u8>) {
+        if !self.nal_filter.forwards_nal(&nal) {
+            return;
+        }
+        if nal_type(&nal) == Some(NAL_IDR) {
+            if let Some((sps, pps)) = &self.parameter_sets {
+                self.worker.send(sps.clone());
+                self.worker.send(pps.clone());
+            }
+        }
+        self.worker.send(with_start_code(nal));
+    }
+
+    // Splits an Annex B byte stream into NAL units and feeds them to the decoder
+    pub fn push_bytes(&self, bytes: &[u8]) {
+        for nal in nal_units(bytes) {
+            self.push_nal_unit(nal.to_vec());
+        }
+    }
+}
+
+// Add this component to an entity that is loading a video from the asset server
+#[derive(Component)]
+pub struct H264DecoderLoading;
+
+// This update is called whenever a decoder has updated the render target image
+// Make sure all materials that read the image are modified
+#[derive(Event)]
+pub struct H264UpdateEvent(pub Entity);
+
+#[derive(Component)]
+pub struct H264DecoderPause;
+
+// Stops only push_packet, frames that are already decoded or queued keep being presented until the queue runs dry
+// Useful to drain the queue to a known point before a seek or stop
+#[derive(Component)]
+pub struct H264DecoderPausePush;
+
+// Stops only presentation, push_packet keeps the queue filled up to the high water mark
+#[derive(Component)]
+pub struct H264DecoderPausePresent;
+
+// Sent when a decoder's render target was removed from Assets<Image>
+// render_target is the replacement when the TargetLostPolicy is Recreate, materials using the old handle have to be rebound to it
+// It is None when the policy is Remove and the H264Decoder component is being removed
+#[derive(Event)]
+pub struct H264RenderTargetLostEvent {
+    pub entity: Entity,
+    pub render_target: Option<Handle<Image>>,
+}
+
+// A read only mirror of a decoder's playback position, inserted next to every H264Decoder once it starts playing
+// Updated every tick, but only marked as changed when something actually changed
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq)]
+pub struct H264PlaybackState {
+    // The frame that is presented next, i.e. the number of frames presented in the current loop
+    pub frame: usize,
+    pub frame_count: usize,
+    pub paused: bool,
+    // The video reached its end without repeat, see PlaybackPhase::Finished
+    pub finished: bool,
+    pub phase: PlaybackPhase,
+    // frame as seconds, at one frame per fixed timestep
+    pub secs: f64,
+}
+
+#[allow(clippy::type_complexity)]
+fn update_playback_state(
+    mut commands: Commands,
+    mut query: Query<(Entity, &H264Decoder, Has<H264DecoderPause>, Option<&mut H264PlaybackState>), Without<H264DecoderLoading>>,
+    time: Res<Time<Fixed>>,
+) {
+    for (entity, decoder, paused, playback_state) in query.iter_mut() {
+        let new_state = H264PlaybackState {
+            frame: decoder.next_frame,
+            frame_count: decoder.frame_count,
+            paused,
+            finished: decoder.phase == PlaybackPhase::Finished,
+            phase: decoder.phase,
+            secs: decoder.next_frame as f64 * time.timestep().as_secs_f64(),
+        };
+        match playback_state {
+            Some(mut playback_state) => {
+                playback_state.set_if_neq(new_state);
+            }
+            None => {
+                commands.entity(entity).insert(new_state);
+            }
+        }
+    }
+}
+
+// Remove the loading flag once a video is done loading
+fn begin_decode(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut H264Decoder), With<H264DecoderLoading>>,
+    asset_server: Res<AssetServer>,
+    videos: Res<Assets<H264Video>>,
+    mut images: ResMut<Assets<Image>>,
+    mut update_ev: EventWriter<H264UpdateEvent>,
+    progressive_loads: Res<ProgressiveLoads>,
+) {
+    for (entity, mut decoder) in query.iter_mut() {
+        if !decoder.load_alpha(&asset_server, &videos) {
+            continue;
+        }
+        // If it is still loading, then ignore unless it is loaded progressively and the first keyframe is in
+        if match asset_server.get_load_state(&decoder.video) {
+            Some(load_state) => matches!(load_state, LoadState::Loading),
+            _ => false,
+        } {
+            let Some(progressive) = decoder.video.path().and_then(|path| progressive_loads.get(path)) else { continue };
+            let Some(start) = first_keyframe_start(&progressive.lock().unwrap()) else { continue };
+            commands.entity(entity).remove::<H264DecoderLoading>();
+            decoder.loop_start = start;
+            decoder.frame_idx = start;
+            decoder.progressive = Some(progressive);
+            continue;
+        }
+        commands.entity(entity).remove::<H264DecoderLoading>();
+        
+        if match asset_server.get_load_state(&decoder.video) {
+            Some(load_state) => matches!(load_state, LoadState::Failed(_)) || matches!(load_state, LoadState::NotLoaded),
+            _ => false,
+        } {
+            commands.entity(entity).remove::<H264Decoder>();
+        } else {
+            if let Some(video) = videos.get(&decoder.video) {
+                decoder.apply_video(video);
+                decoder.frame_idx = video.loop_start;
+                let alpha_frames = decoder.alpha_video(&videos).map(H264Video::frame_count);
+                if let Some(alpha_frames) = alpha_frames.filter(|&alpha_frames| alpha_frames != video.frame_count()) {
+                    warn!("The alpha video has {} frames but the video has {}, playing without alpha", alpha_frames, video.frame_count());
+                    decoder.alpha = None;
+                }
+                let segment_start = decoder.loop_first_frame;
+                let alpha_video = decoder.alpha_video(&videos);
+                if segment_start > 0 && !video.is_degenerate() {
+                    decoder.seek_to_keyframe(video, alpha_video, segment_start);
+                }
+
+                if video.is_degenerate() {
+                    warn!(
+                        "{} has {} NAL units, {} frames and {} keyframes, presenting it once instead of playing it",
+                        decoder.video.path().map_or("Video".to_string(), ToString::to_string),
+                        video.buffer.len(),
+                        video.frame_count(),
+                        video.keyframes().len(),
+                    );
+                    decoder.start_one_shot(video, alpha_video);
+                }
+
+                // Present the cached first frame right away
+                let first_frame = video.first_frame.as_ref()
+                    .filter(|frame| frame.format.texture_format() == decoder.output_format && decoder.presentation == Presentation::RenderTarget && decoder.loop_first_frame == 0)
+                    // The cached frame is opaque and converted with the default options, e.g. it is neither tinted nor flipped
+                    .filter(|_| decoder.alpha.is_none() && decoder.worker.settings.options == ConvertOptions::default());
+                if let Some(frame) = first_frame {
+                    if decoder.upload(&mut images, frame.clone()) {
+                        decoder.first_frame_presented = true;
+                        update_ev.send(H264UpdateEvent(entity));
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Switches progressively playing decoders over to the loaded video, which has the same NAL units
+fn finish_progressive(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut H264Decoder), Without<H264DecoderLoading>>,
+    asset_server: Res<AssetServer>,
+    videos: Res<Assets<H264Video>>,
+) {
+    for (entity, mut decoder) in query.iter_mut() {
+        if decoder.progressive.is_none() {
+            continue;
+        }
+        if let Some(video) = videos.get(&decoder.video) {
+            decoder.apply_video(video);
+            decoder.progressive = None;
+        } else if let Some(LoadState::Failed(_) | LoadState::NotLoaded) = asset_server.get_load_state(&decoder.video) {
+            commands.entity(entity).remove::<H264Decoder>();
+        }
+    }
+}
+
+// Switches decoders to the video passed to set_video_at once it is loaded
+fn swap_video(
+    mut query: Query<&mut H264Decoder, Without<H264DecoderLoading>>,
+    asset_server: Res<AssetServer>,
+    videos: Res<Assets<H264Video>>,
+    time: Res<Time<Fixed>>,
+) {
+    for mut decoder in query.iter_mut() {
+        let Some((video_handle, seconds)) = decoder.pending_video.clone() else { continue };
+        if let Some(LoadState::Failed(err)) = asset_server.get_load_state(&video_handle) {
+            warn!("Could not switch video: {err}");
+            decoder.pending_video = None;
+            continue;
+        }
+        let Some(video) = videos.get(&video_handle) else { continue };
+        decoder.pending_video = None;
+        if video.is_degenerate() {
+            warn!("Could not switch video: it has {} frames and {} keyframes", video.frame_count(), video.keyframes().len());
+            continue;
+        }
+
+        // Videos advance one frame per fixed timestep
+        let frame = (seconds.max(0.0) / time.timestep().as_secs_f64()) as usize;
+        let frame = if decoder.repeat {
+            frame % video.frame_count().max(1)
+        } else {
+            frame.min(video.frame_count().saturating_sub(1))
+        };
+        decoder.video = video_handle;
+        decoder.apply_video(video);
+        // The head of the old video can not be blended into the new one
+        decoder.loop_head.clear();
+        let alpha_video = decoder.alpha_video(&videos);
+        decoder.seek_to_keyframe(video, alpha_video, frame);
+    }
+}
+
+type PresentQuery<'w, 's> = Query<'w, 's, (Entity, &'static mut H264Decoder, Option<&'static ViewVisibility>), (Without<H264DecoderPause>, Without<H264DecoderPausePresent>, Without<H264DecoderLoading>)>;
+
+pub fn decode_video(
+    commands: Commands,
+    query: PresentQuery,
+    images: ResMut<Assets<Image>>,
+    update_ev: EventWriter<H264UpdateEvent>,
+    lost_ev: EventWriter<H264RenderTargetLostEvent>,
+    time: Res<Time<Fixed>>,
+) {
+    let tick = PresentTick { pacing: Pacing::FixedTick, frames: 1.0, frame_secs: time.timestep().as_secs_f64() };
+    present_frames(tick, commands, query, images, update_ev, lost_ev);
+}
+
+// Presents the decoders with Pacing::NearestToDisplay every rendered frame
+fn present_nearest(
+    commands: Commands,
+    query: PresentQuery,
+    images: ResMut<Assets<Image>>,
+    update_ev: EventWriter<H264UpdateEvent>,
+    lost_ev: EventWriter<H264RenderTargetLostEvent>,
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    let frame_secs = fixed_time.timestep().as_secs_f64();
+    let tick = PresentTick { pacing: Pacing::NearestToDisplay, frames: time.delta_secs_f64() / frame_secs, frame_secs };
+    present_frames(tick, commands, query, images, update_ev, lost_ev);
+}
+
+// How far present_frames advances the decoders with the given pacing
+struct PresentTick {
+    pacing: Pacing,
+    frames: f64,
+    // The duration of a frame at speed 1.0
+    frame_secs: f64,
+}
+
+// Advances the clocks of the decoders with the tick's pacing and presents what became due
+fn present_frames(
+    tick: PresentTick,
+    mut commands: Commands,
+    mut query: PresentQuery,
+    mut images: ResMut<Assets<Image>>,
+    mut update_ev: EventWriter<H264UpdateEvent>,
+    mut lost_ev: EventWriter<H264RenderTargetLostEvent>,
+) {
+    for (entity, mut decoder, view_visibility) in query.iter_mut() {
+        if decoder.presentation == Presentation::Manual || decoder.pacing != tick.pacing || decoder.is_stopped() {
+            continue;
+        }
+        decoder.frame_secs = tick.frame_secs;
+        let visible = view_visibility.is_none_or(|view_visibility| view_visibility.get());
+        if !visible && decoder.hidden_behavior == HiddenBehavior::Pause {
+            continue;
+        }
+        let present = visible || decoder.hidden_behavior == HiddenBehavior::Play;
+        if present && !images.contains(&decoder.render_target) {
+            if decoder.ensure_render_target(&mut images) {
+                warn!("Render target of {entity} is missing, recreating it");
+                lost_ev.send(H264RenderTargetLostEvent { entity, render_target: Some(decoder.get_render_target()) });
+            } else {
+                warn!("Render target of {entity} is missing, removing its decoder");
+                lost_ev.send(H264RenderTargetLostEvent { entity, render_target: None });
+                commands.entity(entity).remove::<H264Decoder>();
+                continue;
+            }
+        }
+        if decoder.phase == PlaybackPhase::Finished {
+            // Hold the last frame, see H264DecoderBuilder::auto_pause_on_end
+            continue;
+        }
+        if let Some(audio_secs) = decoder.observed_audio_secs.take() {
+            let drift = audio_secs - decoder.master_time();
+            decoder.audio_drift = Some(drift);
+            // Larger offsets, e.g. right after one side looped or seeked, are left to the user
+            if drift.abs() <= MAX_AUDIO_DRIFT_SECS {
+                let correction = (drift / tick.frame_secs * AUDIO_SYNC_GAIN).clamp(-AUDIO_SYNC_MAX_FRAMES, AUDIO_SYNC_MAX_FRAMES);
+                decoder.clock.nudge(correction);
+            }
+        }
+        let due = decoder.clock.tick(tick.frames);
+        if due == 0 {
+            // No new frame is due yet, hold the current frame
+            continue;
+        }
+        // Above 1x only the last due frame is presented
+        let mut taken = false;
+        let mut latest = None;
+        for _ in 0..due {
+            let Some(frame) = decoder.take_frame() else { break };
+            taken = true;
+            let frame = decoder.crossfade(frame);
+            if decoder.first_frame_presented {
+                // Already on screen from the first frame cache
+                decoder.first_frame_presented = false;
+            } else {
+                latest = Some(frame);
+            }
+            if decoder.advance_frame() && !decoder.repeat {
+                // update_playback_phase pauses or holds the decoder from here
+                decoder.phase = PlaybackPhase::Ending;
+                break;
+            }
+        }
+        if taken {
+            let Some(frame) = latest else { continue };
+            if !present {
+                continue;
+            }
+            if decoder.upload_timing == UploadTiming::Last {
+                // Only the latest frame of this render frame gets uploaded
+                decoder.pending_upload = Some(frame);
+                continue;
+            }
+            if decoder.upload(&mut images, frame) {
+                // Send the event
+                update_ev.send(H264UpdateEvent(entity));
+            }
+        } else if let (true, StallBehavior::Fill(color)) = (present, decoder.stall_behavior) {
+            // Frame is missed, show the fill until the next frame arrives
+            if decoder.stalled {
+                continue;
+            }
+            if let Some(image) = images.get_mut(&decoder.render_target) {
+                let fill = color_to_pixel(color, decoder.output_format);
+                for pixel in image.data.chunks_exact_mut(fill.len()) {
+                    pixel.copy_from_slice(&fill);
+                }
+                decoder.stalled = true;
+                decoder.generation += 1;
+                update_ev.send(H264UpdateEvent(entity));
+            }
+        }
+        // Otherwise the last frame is held until the next game tick
+    }
+}
+
+fn decode_stream(
+    mut query: Query<(Entity, &mut H264StreamDecoder)>,
+    mut images: ResMut<Assets<Image>>,
+    mut update_ev: EventWriter<H264UpdateEvent>,
+) {
+    for (entity, mut decoder) in query.iter_mut() {
+        let Some(frame) = decoder.worker.take_latest_frame() else { continue };
+        decoder.latency.record(&frame);
+        let Some(image) = images.get_mut(&decoder.render_target) else { continue };
+        if !upload_frame(image, frame) {
+            continue;
+        }
+        decoder.output_size = image.texture_descriptor.size;
+        decoder.output_format = image.texture_descriptor.format;
+        update_ev.send(H264UpdateEvent(entity));
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn push_packet(
+    mut query: Query<(&mut H264Decoder, Option<&ViewVisibility>), (Without<H264DecoderLoading>, Without<H264DecoderPause>, Without<H264DecoderPausePush>)>,
+    videos: Res<Assets<H264Video>>,
+) {
+    for (mut decoder, view_visibility) in query.iter_mut() {
+        if decoder.is_stopped() || (decoder.hidden_behavior == HiddenBehavior::Pause && view_visibility.is_some_and(|view_visibility| !view_visibility.get())) {
+            continue;
+        }
+        if let Some(frame) = decoder.seek_pending {
+            let Some(video) = videos.get(&decoder.video) else { continue };
+            decoder.seek_pending = None;
+            let alpha_video = decoder.alpha_video(&videos);
+            if decoder.one_shot {
+                decoder.push_whole(video, alpha_video);
+            } else {
+                decoder.seek_to_keyframe(video, alpha_video, frame);
+            }
+        }
+        if decoder.one_shot {
+            continue;
+        }
+        // Only push more packets once the buffer has drained below the low water mark
+        let mut buffer_size = decoder.worker.queued_frames();
+        if buffer_size >= decoder.low_water_mark {
+            continue;
+        }
+        let alpha_video = decoder.alpha_video(&videos);
+        if let Some(video) = videos.get(&decoder.video) {
+            decoder.fill_buffer(video, alpha_video, buffer_size);
+        } else if let Some(progressive) = decoder.progressive.clone() {
+            // Push what has been loaded so far, the rest follows on later ticks
+            let progressive = progressive.lock().unwrap();
+            while buffer_size < decoder.high_water_mark && decoder.frame_idx < progressive.len() {
+                let nal = progressive[decoder.frame_idx].clone();
+                decoder.add_video_packet(nal, alpha_video);
+                decoder.frame_idx += 1;
+                buffer_size += 1;
+            }
+        }
+    }
+}
+
+// Moves decoders between PlaybackPhases and sends H264PlaybackPhaseEvent for every step
+// A finished video plays again from the frames queued past its end, which are the start of the next loop
+#[allow(clippy::type_complexity)]
+fn update_playback_phase(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut H264Decoder, Has<H264DecoderLoading>, Has<H264DecoderPause>)>,
+    mut phase_ev: EventWriter<H264PlaybackPhaseEvent>,
+) {
+    for (entity, mut decoder, loading, mut paused) in query.iter_mut() {
+        loop {
+            let phase = decoder.phase;
+            if phase != decoder.reported_phase {
+                phase_ev.send(H264PlaybackPhaseEvent { entity, previous: decoder.reported_phase, phase });
+                decoder.reported_phase = phase;
+            }
+            let next = match phase {
+                _ if loading => PlaybackPhase::Loading,
+                PlaybackPhase::Ending | PlaybackPhase::Finished if decoder.repeat => {
+                    // Only the end pause is lifted, a pause inserted by the user stays
+                    if paused && decoder.auto_pause_on_end {
+                        commands.entity(entity).remove::<H264DecoderPause>();
+                        paused = false;
+                    }
+                    if paused { PlaybackPhase::Paused } else { PlaybackPhase::Playing }
+                }
+                PlaybackPhase::Ending => {
+                    if decoder.auto_pause_on_end && !paused {
+                        commands.entity(entity).insert(H264DecoderPause);
+                        paused = true;
+                    }
+                    PlaybackPhase::Finished
+                }
+                // The end pause was removed, which plays the next loop
+                PlaybackPhase::Finished if decoder.auto_pause_on_end && !paused => PlaybackPhase::Playing,
+                PlaybackPhase::Finished => PlaybackPhase::Finished,
+                _ if paused => PlaybackPhase::Paused,
+                _ => PlaybackPhase::Playing,
+            };
+            if next == phase {
+                break;
+            }
+            decoder.phase = next;
+        }
+    }
+}
+
+// Marks the materials and UI images of decoders that presented a frame as changed, see H264Plugin::update_materials
+// Runs after H264Systems::Upload so frames deferred by UploadTiming::Last are covered too
+// Material assets that are not registered in the app are skipped
+#[cfg(feature = "materials")]
+fn touch_materials(
+    mut update_ev: EventReader<H264UpdateEvent>,
+    standard_query: Query<&MeshMaterial3d<StandardMaterial>>,
+    color_query: Query<&MeshMaterial2d<ColorMaterial>>,
+    mut image_node_query: Query<&mut ImageNode>,
+    mut standard_materials: Option<ResMut<Assets<StandardMaterial>>>,
+    mut color_materials: Option<ResMut<Assets<ColorMaterial>>>,
+) {
+    for update in update_ev.read() {
+        if let (Ok(material), Some(materials)) = (standard_query.get(update.0), standard_materials.as_mut()) {
+            let _ = materials.get_mut(&material.0);
+        }
+        if let (Ok(material), Some(materials)) = (color_query.get(update.0), color_materials.as_mut()) {
+            let _ = materials.get_mut(&material.0);
+        }
+        if let Ok(mut image_node) = image_node_query.get_mut(update.0) {
+            image_node.set_changed();
+        }
+    }
+}
+
+// Applies the ErrorPolicy of decoders whose packets keep failing to decode
+fn handle_decode_errors(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut H264Decoder), (Without<H264DecoderLoading>, Without<H264DecoderPause>)>,
+    videos: Res<Assets<H264Video>>,
+    mut error_ev: EventWriter<H264DecodeErrorEvent>,
+) {
+    for (entity, mut decoder) in query.iter_mut() {
+        let limit = match decoder.error_policy {
+            ErrorPolicy::Ignore => continue,
+            ErrorPolicy::StopAfter(limit) | ErrorPolicy::RestartAfter(limit) => limit as usize,
+        };
+        if decoder.worker.consecutive_errors() < limit.max(1) {
+            continue;
+        }
+        let consecutive_errors = decoder.worker.take_consecutive_errors();
+        let restarted = match decoder.error_policy {
+            ErrorPolicy::RestartAfter(_) => {
+                warn!("{consecutive_errors} consecutive decode errors in {entity}, restarting from the last keyframe");
+                if let Some(video) = videos.get(&decoder.video) {
+                    let frame = decoder.next_frame;
+                    let alpha_video = decoder.alpha_video(&videos);
+                    decoder.seek_to_keyframe(video, alpha_video, frame);
+                }
+                true
+            }
+            _ => {
+                warn!("{consecutive_errors} consecutive decode errors in {entity}, stopping it");
+                commands.entity(entity).insert(H264DecoderPause);
+                false
+            }
+        };
+        error_ev.send(H264DecodeErrorEvent { entity, consecutive_errors, restarted });
+    }
+}
+
+// Samples the decode threads' counters for throughput, in real time so it is not affected by pausing or time scaling
+fn update_throughput(
+    mut decoders: Query<&mut H264Decoder>,
+    mut streams: Query<&mut H264StreamDecoder>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed_secs_f64();
+    for mut decoder in decoders.iter_mut() {
+        decoder.worker.update_throughput(now);
+    }
+    for mut stream in streams.iter_mut() {
+        stream.worker.update_throughput(now);
+    }
+}
+
+// Uploads the frames deferred by UploadTiming::Last
+fn upload_pending(
+    mut query: Query<(Entity, &mut H264Decoder)>,
+    mut images: ResMut<Assets<Image>>,
+    mut update_ev: EventWriter<H264UpdateEvent>,
+) {
+    for (entity, mut decoder) in query.iter_mut() {
+        let Some(frame) = decoder.pending_upload.take() else { continue };
+        if decoder.upload(&mut images, frame) {
+            update_ev.send(H264UpdateEvent(entity));
+        }
+    }
+}
+
+// Honors PauseDisplay::Clear whenever a pause is applied
+// While paused decode_video does not run, so a held frame causes no further uploads or events
+fn apply_pause_display(
+    mut query: Query<(Entity, &mut H264Decoder), Added<H264DecoderPause>>,
+    mut images: ResMut<Assets<Image>>,
+    mut update_ev: EventWriter<H264UpdateEvent>,
+) {
+    for (entity, mut decoder) in query.iter_mut() {
+        if decoder.pause_display != PauseDisplay::Clear {
+            continue;
+        }
+        // The cleared target must not be overwritten by a deferred upload
+        decoder.pending_upload = None;
+        if let Some(image) = images.get_mut(&decoder.render_target) {
+            image.data.fill(0);
+            decoder.generation += 1;
+            update_ev.send(H264UpdateEvent(entity));
+        }
+    }
+}
+
+// This event makes no garuntees on what the real frame will be
+// If the video is not suppose to restart, then you should make sure it has been paused (ideally for a short amount of time)
+// If the video is paused it will clear out the image queue
+#[derive(Event)]
+pub struct H264RestartEvent(Entity);
+
+fn restart_video(
+    mut query: Query<(&mut H264Decoder, Has<H264DecoderPause>), Without<H264DecoderLoading>>,
+    mut restart_ev: EventReader<H264RestartEvent>,
+    videos: Res<Assets<H264Video>>,
+) {
+    for event in restart_ev.read() {
+        if let Ok((mut decoder, is_paused)) = query.get_mut(event.0) {
+            if decoder.loop_first_frame > 0 {
+                // Restart from the start of the segment
+                let Some(video) = videos.get(&decoder.video) else { continue };
+                let first_frame = decoder.loop_first_frame;
+                let alpha_video = decoder.alpha_video(&videos);
+                decoder.seek_to_keyframe(video, alpha_video, first_frame);
+                continue;
+            }
+            decoder.frame_idx = decoder.loop_start;
+            decoder.next_frame = 0;
+            decoder.start_loop(0);
+            decoder.clock.reset();
+            decoder.pending_upload = None;
+            decoder.leave_end();
+            decoder.first_frame_presented = false;
+            if is_paused {
+                decoder.worker.clear_frames();
+            }
+        }
+    }
+}
+
+// Prepares a decoder to play from the last keyframe at or before frame, without presenting anything
+// The GOP is decoded in the background right away, so when the decoder plays next it starts from there instantly
+// Send it while the decoder is paused, e.g. when a seek bar is hovered, and remove H264DecoderPause to commit
+// On a playing decoder this is the same as seeking
+#[derive(Event)]
+pub struct H264PrimeEvent {
+    pub entity: Entity,
+    pub frame: usize,
+}
+
+fn prime_video(
+    mut query: Query<&mut H264Decoder, Without<H264DecoderLoading>>,
+    mut prime_ev: EventReader<H264PrimeEvent>,
+    videos: Res<Assets<H264Video>>,
+) {
+    for event in prime_ev.read() {
+        let Ok(mut decoder) = query.get_mut(event.entity) else { continue };
+        let Some(video) = videos.get(&decoder.video) else { continue };
+        if decoder.one_shot {
+            continue;
+        }
+        let alpha_video = decoder.alpha_video(&videos);
+        decoder.seek_to_keyframe(video, alpha_video, event.frame);
+        // push_packet skips paused decoders, so fill the buffer here
+        decoder.fill_buffer(video, alpha_video, 0);
+    }
+}
+
+// How the half resolution U and V planes are scaled up to the luma resolution
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChromaUpsampling {
+    // Each chroma sample covers its 2x2 block of pixels. Fastest, but color edges look blocky, especially on diagonals
+    #[default]
+    Nearest,
+    // Interpolates between the surrounding chroma samples, which sit at the center of their 2x2 block
+    Bilinear,
+}
+
+// The two chroma samples surrounding a pixel along one axis, and the weight of the second one
+fn bilinear_taps(pos: usize, chroma_len: usize) -> (usize, usize, f32) {
+    let center = (pos as f32 - 0.5) / 2.0;
+    let low = center.floor().max(0.0);
+    let weight = (center - low).clamp(0.0, 1.0);
+    let low = (low as usize).min(chroma_len - 1);
+    (low, (low + 1).min(chroma_len - 1), weight)
+}
+
+// Pixel format of a decoder's render target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    // 8 bit sRGB, 4 bytes per pixel
+    #[default]
+    Bgra8UnormSrgb,
+    // 8 bit sRGB in RGBA order, for bevy_ui ImageNode and other paths that expect RGBA, see H264DecoderBuilder::for_ui
+    Rgba8UnormSrgb,
+    // Linear 16 bit float for HDR pipelines, so the video gets the same headroom for bloom and tonemapping as the rest of the scene
+    // Uses 8 bytes per pixel, twice the memory and upload bandwidth of Bgra8UnormSrgb
+    Rgba16Float,
+    // The sRGB encoded values of Rgba8UnormSrgb in a texture without the sRGB view, which can be bound as a storage texture
+    // Shaders read the encoded values, use Rgba16Float for linear ones. See H264DecoderBuilder::for_compute
+    Rgba8Unorm,
+}
+
+impl OutputFormat {
+    fn texture_format(self) -> TextureFormat {
+        match self {
+            OutputFormat::Bgra8UnormSrgb => TextureFormat::Bgra8UnormSrgb,
+            OutputFormat::Rgba8UnormSrgb => TextureFormat::Rgba8UnormSrgb,
+            OutputFormat::Rgba16Float => TextureFormat::Rgba16Float,
+            OutputFormat::Rgba8Unorm => TextureFormat::Rgba8Unorm,
+        }
+    }
+
+    fn pixel_size(self) -> usize {
+        match self {
+            OutputFormat::Bgra8UnormSrgb | OutputFormat::Rgba8UnormSrgb | OutputFormat::Rgba8Unorm => 4,
+            OutputFormat::Rgba16Float => 8,
+        }
+    }
+}
+
+// Whether the luma and chroma samples use the whole 0-255 range or the limited 16-235 and 16-240 video range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorRange {
+    #[default]
+    Full,
+    Limited,
+}
+
+// The YUV to RGB matrix the video was encoded with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMatrix {
+    // Standard definition, BT.601
+    #[default]
+    Bt601,
+    // High definition, BT.709
+    Bt709,
+}
+
+impl ColorMatrix {
+    // The factors of V for red, U and V for green, and U for blue
+    fn coefficients(self) -> (f32, f32, f32, f32) {
+        match self {
+            ColorMatrix::Bt601 => (1.402, 0.344, 0.714, 1.772),
+            ColorMatrix::Bt709 => (1.5748, 0.1873, 0.4681, 1.8556),
+        }
+    }
+}
+
+// How YUV pictures are converted into pixels, see yuv_to_bgra8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConvertOptions {
+    pub chroma_upsampling: ChromaUpsampling,
+    pub range: ColorRange,
+    pub matrix: ColorMatrix,
+    // Writes the rows bottom to top
+    pub flip_y: bool,
+    // Multiplies every pixel with this color in sRGB space. White leaves the picture untouched
+    pub tint: Color,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            chroma_upsampling: ChromaUpsampling::default(),
+            range: ColorRange::default(),
+            matrix: ColorMatrix::default(),
+            flip_y: false,
+            tint: Color::WHITE,
+        }
+    }
+}
+
+// How the decode thread converts YUV pictures into render target pixels
+#[derive(Clone, Copy, Debug, Default)]
+struct ConvertSettings {
+    options: ConvertOptions,
+    output_format: OutputFormat,
+    // Decode a companion alpha video into the alpha channel, see H264DecoderBuilder::alpha_video
+    alpha: bool,
+}
+
+// The sRGB transfer function, converting an encoded value to linear light
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// Byte layout of converted pixels
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PixelLayout {
+    Bgra8,
+    Rgba8,
+    Rgba16Float,
+}
+
+impl From<OutputFormat> for PixelLayout {
+    fn from(format: OutputFormat) -> Self {
+        match format {
+            OutputFormat::Bgra8UnormSrgb => PixelLayout::Bgra8,
+            OutputFormat::Rgba8UnormSrgb | OutputFormat::Rgba8Unorm => PixelLayout::Rgba8,
+            OutputFormat::Rgba16Float => PixelLayout::Rgba16Float,
         }
+    }
+}
+
+// Converts a decoded picture into 8 bit sRGB BGRA pixels, the layout of OutputFormat::Bgra8UnormSrgb
+// This is the conversion the decode thread uses
+pub fn yuv_to_bgra8(decoded_yuv: &DecodedYUV, options: ConvertOptions) -> Vec<u8> {
+    convert_yuv(&YuvPlanes::new(decoded_yuv), options, PixelLayout::Bgra8)
+}
+
+// Same as yuv_to_bgra8 with red and blue swapped, the layout of OutputFormat::Rgba8UnormSrgb and OutputFormat::Rgba8Unorm
+pub fn yuv_to_rgba8(decoded_yuv: &DecodedYUV, options: ConvertOptions) -> Vec<u8> {
+    convert_yuv(&YuvPlanes::new(decoded_yuv), options, PixelLayout::Rgba8)
+}
+
+// Converts a decoded picture into linear little endian f16 RGBA pixels, the layout of OutputFormat::Rgba16Float
+pub fn yuv_to_rgba16f(decoded_yuv: &DecodedYUV, options: ConvertOptions) -> Vec<u8> {
+    convert_yuv(&YuvPlanes::new(decoded_yuv), options, PixelLayout::Rgba16Float)
+}
+
+// The 4:2:0 planes of a picture, borrowed from a DecodedYUV
+// Kept apart from it so the conversion also runs on planes that did not come out of openh264, such as synthetic test pictures
+struct YuvPlanes<'a> {
+    // Luma size in pixels
+    dim: (usize, usize),
+    strides: (usize, usize, usize),
+    y: &'a [u8],
+    u: &'a [u8],
+    v: &'a [u8],
+}
+
+impl<'a> YuvPlanes<'a> {
+    fn new(decoded_yuv: &'a DecodedYUV) -> Self {
+        Self {
+            dim: decoded_yuv.dimension_rgb(),
+            strides: decoded_yuv.strides_yuv(),
+            y: decoded_yuv.y_with_stride(),
+            u: decoded_yuv.u_with_stride(),
+            v: decoded_yuv.v_with_stride(),
+        }
+    }
+}
+
+// Mixes other into frame with the given weight, e.g. for a crossfade
+// Frames of different sizes or formats are left alone
+fn blend_frames(frame: &mut VideoFrame, other: &VideoFrame, weight: f32) {
+    if frame.format != other.format || frame.width != other.width || frame.height != other.height {
+        return;
+    }
+    match frame.format {
+        OutputFormat::Bgra8UnormSrgb | OutputFormat::Rgba8UnormSrgb | OutputFormat::Rgba8Unorm => {
+            for (value, other) in frame.buffer.iter_mut().zip(&other.buffer) {
+                *value = (*value as f32 * (1.0 - weight) + *other as f32 * weight).round() as u8;
+            }
+        }
+        OutputFormat::Rgba16Float => {
+            for (value, other) in frame.buffer.chunks_exact_mut(2).zip(other.buffer.chunks_exact(2)) {
+                let mixed = f16::from_le_bytes([value[0], value[1]]).to_f32() * (1.0 - weight) + f16::from_le_bytes([other[0], other[1]]).to_f32() * weight;
+                value.copy_from_slice(&f16::from_f32(mixed).to_le_bytes());
+            }
+        }
+    }
+}
+
+// Skips a step of copying by just creating the buffer in the right format
+fn convert_yuv(planes: &YuvPlanes, options: ConvertOptions, layout: PixelLayout) -> Vec<u8> {
+    let dim = planes.dim;
+    let strides = planes.strides;
+    let pixel_size = match layout {
+        PixelLayout::Bgra8 | PixelLayout::Rgba8 => 4,
+        PixelLayout::Rgba16Float => 8,
+    };
+    let size = dim.0 * dim.1 * pixel_size;
+    let chroma_dim = (dim.0.div_ceil(2), dim.1.div_ceil(2));
+    let (rv, gu, gv, bu) = options.matrix.coefficients();
+    let tint = options.tint.to_srgba();
+    // Limited range is stretched to full range before the matrix is applied
+    let (luma_offset, luma_scale, chroma_scale) = match options.range {
+        ColorRange::Full => (0.0, 1.0, 1.0),
+        ColorRange::Limited => (16.0, 255.0 / 219.0, 255.0 / 224.0),
+    };
+
+    let mut result = vec![0; size];
+
+    // Interpolates a chroma plane with the precomputed taps of a pixel
+    let sample = |plane: &[u8], stride: usize, (x0, x1, wx): (usize, usize, f32), (y0, y1, wy): (usize, usize, f32)| {
+        let top = plane[y0 * stride + x0] as f32 * (1.0 - wx) + plane[y0 * stride + x1] as f32 * wx;
+        let bottom = plane[y1 * stride + x0] as f32 * (1.0 - wx) + plane[y1 * stride + x1] as f32 * wx;
+        top * (1.0 - wy) + bottom * wy
+    };
+    let x_taps: Vec<_> = (0..dim.0).map(|x| bilinear_taps(x, chroma_dim.0)).collect();
+
+    for y in 0..dim.1 {
+        let y_taps = bilinear_taps(y, chroma_dim.1);
+        let target_row = if options.flip_y { dim.1 - 1 - y } else { y };
+        for (x, &x_tap) in x_taps.iter().enumerate() {
+            let base_tgt = (target_row * dim.0 + x) * pixel_size;
+            let base_y = y * strides.0 + x;
+
+            let pixel = &mut result[base_tgt..base_tgt + pixel_size];
+
+            let (u, v) = match options.chroma_upsampling {
+                ChromaUpsampling::Nearest => {
+                    let base_u = (y / 2 * strides.1) + (x / 2);
+                    let base_v = (y / 2 * strides.2) + (x / 2);
+                    (planes.u[base_u] as f32, planes.v[base_v] as f32)
+                }
+                ChromaUpsampling::Bilinear => (
+                    sample(planes.u, strides.1, x_tap, y_taps),
+                    sample(planes.v, strides.2, x_tap, y_taps),
+                ),
+            };
+            let y = (planes.y[base_y] as f32 - luma_offset) * luma_scale;
+            let u = (u - 128.0) * chroma_scale;
+            let v = (v - 128.0) * chroma_scale;
+
+            let r = (y + rv * v) * tint.red;
+            let g = (y - gu * u - gv * v) * tint.green;
+            let b = (y + bu * u) * tint.blue;
+
+            match layout {
+                PixelLayout::Bgra8 => {
+                    pixel[2] = r as u8;
+                    pixel[1] = g as u8;
+                    pixel[0] = b as u8;
+                    pixel[3] = 255;
+                }
+                PixelLayout::Rgba8 => {
+                    pixel[0] = r as u8;
+                    pixel[1] = g as u8;
+                    pixel[2] = b as u8;
+                    pixel[3] = 255;
+                }
+                PixelLayout::Rgba16Float => {
+                    let channels = [r, g, b].map(|channel| srgb_to_linear((channel / 255.0).clamp(0.0, 1.0)));
+                    for (idx, channel) in channels.into_iter().chain([1.0]).enumerate() {
+                        pixel[idx * 2..idx * 2 + 2].copy_from_slice(&f16::from_f32(channel).to_le_bytes());
+                    }
+                }
+            }
+        }
+    }
+    result
+}
+
+// The system sets of the plugin, to order systems that react to H264UpdateEvent
+// H264UpdateEvent consumers should run after Present, or after Upload for decoders using UploadTiming::Last
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum H264Systems {
+    // Starting playback and switching videos, in PreUpdate
+    Load,
+    // Presenting decoded frames in FixedUpdate, and pushing packets and Pacing::NearestToDisplay in Update
+    Present,
+    // Deferred uploads of UploadTiming::Last, in Last
+    Upload,
+}
+
+// Videos are paced by FixedUpdate, so all videos play at the fixed timestep
+// fps is opt-in: when set, the fixed timestep is changed to the given FPS,
+// unless another plugin already configured a non default timestep, in which case that one is kept and a warning is logged
+// Set this to None to leave Time<Fixed> untouched
+#[derive(Default)]
+pub struct H264Plugin {
+    pub fps: Option<f64>,
+    // Creates and drops a decoder while the plugin is built, so a missing or broken openh264 shows up at startup
+    // instead of as a decode thread dying at the first playback. The result is stored in H264DecoderAvailable
+    pub validate_decoder: bool,
+    // Touches the StandardMaterial, ColorMaterial or ImageNode on a decoder's entity whenever it presents a new frame,
+    // so the new frame shows up without a system like modify_materials in the README. Requires the materials feature
+    pub update_materials: bool,
+}
+
+// Whether a decoder could be created when the plugin was built
+// Only inserted when H264Plugin::validate_decoder is set
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct H264DecoderAvailable(pub bool);
+
+// The folder of the default asset source, as configured by AssetPlugin::file_path
+// Only known when AssetPlugin was added first and reads unprocessed files from disk
+#[cfg(not(target_arch = "wasm32"))]
+fn index_root(app: &bevy_app::App) -> Option<PathBuf> {
+    let asset_plugin = app.get_added_plugins::<AssetPlugin>().into_iter().next()?;
+    matches!(asset_plugin.mode, AssetMode::Unprocessed)
+        .then(|| bevy_asset::io::file::FileAssetReader::get_base_path().join(&asset_plugin.file_path))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn index_root(_app: &bevy_app::App) -> Option<PathBuf> {
+    None
+}
+
+// Marks that H264Plugin was built, so adding it again does not register the systems twice
+#[derive(Resource)]
+struct H264PluginRegistered {
+    fps: Option<f64>,
+}
+
+impl Plugin for H264Plugin {
+    fn build(&self, app: &mut bevy_app::App) {
+        // Adding the plugin again, e.g. through two plugins that both depend on it, is a no op
+        if let Some(registered) = app.world().get_resource::<H264PluginRegistered>() {
+            if registered.fps != self.fps {
+                warn!("H264Plugin was already added with fps {:?}, ignoring the second instance with fps {:?}", registered.fps, self.fps);
+            }
+            return;
+        }
+        app.insert_resource(H264PluginRegistered { fps: self.fps });
+
+        if self.validate_decoder {
+            let available = match Decoder::with_config(DecoderConfig::new()) {
+                Ok(_) => true,
+                Err(err) => {
+                    error!("H264Plugin: could not create an openh264 decoder, videos will not play: {err}");
+                    false
+                }
+            };
+            app.insert_resource(H264DecoderAvailable(available));
+        }
+
+        if let Some(fps) = self.fps {
+            match app.world_mut().get_resource_mut::<Time<Fixed>>() {
+                Some(time) if time.timestep() != Time::<Fixed>::default().timestep() => {
+                    warn!(
+                        "H264Plugin: Time<Fixed> is already set to {:?}, ignoring fps {}. Set fps to None to silence this warning",
+                        time.timestep(),
+                        fps,
+                    );
+                }
+                // Only change the timestep so the accumulated time of the existing resource is kept
+                Some(mut time) => time.set_timestep_hz(fps),
+                None => {
+                    app.insert_resource(Time::<Fixed>::from_hz(fps));
+                }
+            }
+        }
+        if self.update_materials {
+            #[cfg(feature = "materials")]
+            app.add_systems(Last, touch_materials.after(H264Systems::Upload));
+            #[cfg(not(feature = "materials"))]
+            warn!("H264Plugin: update_materials requires the materials feature, materials will not be updated");
+        }
+
+        let progressive_loads = ProgressiveLoads::default();
         app
             .add_event::<H264UpdateEvent>()
             .add_event::<H264RestartEvent>()
+            .add_event::<H264RenderTargetLostEvent>()
+            .add_event::<H264PrimeEvent>()
+            .add_event::<H264DecodeErrorEvent>()
+            .add_event::<H264PlaybackPhaseEvent>()
             .init_asset::<H264Video>()
-            .init_asset_loader::<H264VideoLoader>()
-            .add_systems(PreUpdate, begin_decode)
-            .add_systems(FixedUpdate, decode_video)
-            .add_systems(Update, (push_packet, restart_video).chain());
+            .insert_resource(progressive_loads.clone())
+            .register_asset_loader(H264VideoLoader { progressive_loads, index_root: index_root(app) })
+            .add_systems(PreUpdate, (begin_decode, finish_progressive, swap_video).in_set(H264Systems::Load))
+            .add_systems(FixedUpdate, ((decode_video, update_playback_phase, apply_pause_display, update_playback_state).chain(), decode_stream).in_set(H264Systems::Present))
+            // update_playback_phase runs once in each schedule, after the presenting system that can reach the end of the video
+            .add_systems(Update, (push_packet, restart_video, prime_video, present_nearest, update_playback_phase, update_playback_state).chain().in_set(H264Systems::Present))
+            .add_systems(Update, (update_throughput, handle_decode_errors))
+            .add_systems(Last, upload_pending.in_set(H264Systems::Upload));
+    }
+
+    // Duplicates are handled in build instead of panicking
+    fn is_unique(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 4x2 picture with the given luma and one row of two chroma samples per plane
+    fn planes<'a>(y: &'a [u8], u: &'a [u8], v: &'a [u8]) -> YuvPlanes<'a> {
+        YuvPlanes { dim: (4, 2), strides: (4, 2, 2), y, u, v }
+    }
+
+    #[test]
+    fn bilinear_taps_interpolate_between_neighbouring_samples() {
+        // Chroma samples sit between two luma columns, so the edges clamp and the inner pixels blend
+        assert_eq!(bilinear_taps(0, 2), (0, 1, 0.0));
+        assert_eq!(bilinear_taps(1, 2), (0, 1, 0.25));
+        assert_eq!(bilinear_taps(2, 2), (0, 1, 0.75));
+        assert_eq!(bilinear_taps(3, 2), (1, 1, 0.25));
+    }
+
+    #[test]
+    fn bilinear_chroma_smooths_a_gradient() {
+        let (y, u, v) = ([128; 8], [96, 160], [128, 128]);
+        let blue = |upsampling| {
+            let options = ConvertOptions { chroma_upsampling: upsampling, ..Default::default() };
+            let pixels = convert_yuv(&planes(&y, &u, &v), options, PixelLayout::Bgra8);
+            pixels.chunks_exact(4).take(4).map(|pixel| pixel[0]).collect::<Vec<_>>()
+        };
+        let nearest = blue(ChromaUpsampling::Nearest);
+        let bilinear = blue(ChromaUpsampling::Bilinear);
+        // Nearest steps straight from one sample to the other in the middle of the row
+        assert_eq!(nearest[0], nearest[1]);
+        assert_eq!(nearest[2], nearest[3]);
+        assert!(nearest[1] < nearest[2]);
+        // Bilinear keeps the ends and ramps through the middle
+        assert_eq!(bilinear[0], nearest[0]);
+        assert_eq!(bilinear[3], nearest[3]);
+        assert!(bilinear[0] < bilinear[1] && bilinear[1] < bilinear[2] && bilinear[2] < bilinear[3]);
+    }
+
+    // A decoder for a video that is never loaded, decoding inline so tests do not spawn threads
+    fn test_decoder(images: &mut Assets<Image>) -> H264Decoder {
+        H264Decoder::builder(Handle::default()).decode_mode(DecodeMode::Sync).build(images)
+    }
+
+    #[test]
+    fn turning_repeat_on_after_the_end_plays_again() {
+        let mut app = bevy_app::App::new();
+        app.add_event::<H264PlaybackPhaseEvent>().add_systems(Update, update_playback_phase);
+        let mut images = Assets::<Image>::default();
+        let mut decoder = test_decoder(&mut images);
+        // As left by present_frames on the last frame of a non repeating video
+        decoder.phase = PlaybackPhase::Ending;
+        let entity = app.world_mut().spawn(decoder).id();
+
+        app.update();
+        assert_eq!(app.world().get::<H264Decoder>(entity).unwrap().phase(), PlaybackPhase::Finished);
+        assert!(app.world().get::<H264DecoderPause>(entity).is_some());
+
+        app.world_mut().get_mut::<H264Decoder>(entity).unwrap().set_repeat(true);
+        app.update();
+        assert_eq!(app.world().get::<H264Decoder>(entity).unwrap().phase(), PlaybackPhase::Playing);
+        assert!(app.world().get::<H264DecoderPause>(entity).is_none());
+    }
+
+    #[test]
+    fn mid_gray_converts_to_linear_float() {
+        let (y, u, v) = ([128; 8], [128; 2], [128; 2]);
+        let pixels = convert_yuv(&planes(&y, &u, &v), ConvertOptions::default(), PixelLayout::Rgba16Float);
+        assert_eq!(pixels.len(), 4 * 2 * 8);
+        let channels: Vec<f32> = pixels[..8].chunks_exact(2).map(|value| f16::from_le_bytes([value[0], value[1]]).to_f32()).collect();
+        // sRGB 128/255 is 0.2158 in linear light
+        for channel in &channels[..3] {
+            assert!((channel - 0.2158).abs() < 1e-3, "{channel}");
+        }
+        assert_eq!(channels[3], 1.0);
+    }
+
+    #[derive(Resource, Default)]
+    struct UpdateCount(usize);
+
+    fn count_updates(mut update_ev: EventReader<H264UpdateEvent>, mut count: ResMut<UpdateCount>) {
+        count.0 += update_ev.read().count();
     }
-}
\ No newline at end of file
+
+    // The parameter sets and the first picture of the example video
+    fn single_frame_video() -> H264Video {
+        H264Video::from_nal_units(nal_units(include_bytes!("../example/assets/test.h264")).take(4).map(|nal| nal.to_vec()).collect())
+    }
+
+    #[test]
+    fn single_frame_videos_are_presented_once() {
+        let video = single_frame_video();
+        assert_eq!(video.frame_count(), 1);
+        assert!(video.is_degenerate());
+
+        let mut app = bevy_app::App::new();
+        app.init_resource::<Assets<H264Video>>()
+            .init_resource::<Assets<Image>>()
+            .init_resource::<Time<Fixed>>()
+            .init_resource::<UpdateCount>()
+            .add_event::<H264UpdateEvent>()
+            .add_event::<H264RenderTargetLostEvent>()
+            .add_systems(Update, (push_packet, decode_video, count_updates).chain());
+        let mut decoder = {
+            let mut images = app.world_mut().resource_mut::<Assets<Image>>();
+            test_decoder(&mut images)
+        };
+        decoder.frame_count = video.frame_count();
+        decoder.start_one_shot(&video, None);
+        decoder.video = app.world_mut().resource_mut::<Assets<H264Video>>().add(video);
+        let entity = app.world_mut().spawn(decoder).id();
+
+        // Keep ticking in case anything is sent again
+        for _ in 0..10 {
+            app.update();
+        }
+        let decoder = app.world().get::<H264Decoder>(entity).unwrap();
+        assert_eq!(decoder.frame_idx, decoder.loop_start);
+        assert_eq!(app.world().resource::<UpdateCount>().0, 1);
+    }
+
+    fn video_frame(width: usize, height: usize) -> VideoFrame {
+        VideoFrame {
+            buffer: vec![0; width * height * 4],
+            width,
+            height,
+            format: OutputFormat::default(),
+            position: None,
+            sent_at: None,
+        }
+    }
+
+    #[test]
+    fn zero_size_frames_are_not_uploaded() {
+        let mut app = bevy_app::App::new();
+        app.init_resource::<Assets<Image>>()
+            .init_resource::<Time<Fixed>>()
+            .init_resource::<UpdateCount>()
+            .add_event::<H264UpdateEvent>()
+            .add_event::<H264RenderTargetLostEvent>()
+            .add_systems(Update, (decode_video, count_updates).chain());
+        let decoder = {
+            let mut images = app.world_mut().resource_mut::<Assets<Image>>();
+            test_decoder(&mut images)
+        };
+        let render_target = decoder.get_render_target();
+        let size = app.world().resource::<Assets<Image>>().get(&render_target).unwrap().texture_descriptor.size;
+        // As if the decoder had produced a picture without pixels
+        decoder.worker.next_frame_rgb8.lock().unwrap().push_back(video_frame(0, 0));
+        let entity = app.world_mut().spawn(decoder).id();
+
+        app.update();
+        let decoder = app.world().get::<H264Decoder>(entity).unwrap();
+        assert!(decoder.worker.next_frame_rgb8.lock().unwrap().is_empty());
+        assert_eq!(decoder.generation(), 0);
+        assert_eq!(app.world().resource::<Assets<Image>>().get(&render_target).unwrap().texture_descriptor.size, size);
+        assert_eq!(app.world().resource::<UpdateCount>().0, 0);
+    }
+
+    // Parameter sets in front of an IDR picture and two P pictures, the second one split into two slices
+    fn hand_built_video() -> H264Video {
+        H264Video::from_nal_units(vec![
+            vec![0, 0, 0, 1, 0x67, 0x42],
+            vec![0, 0, 0, 1, 0x68, 0xCE],
+            vec![0, 0, 0, 1, 0x65, 0x88],
+            vec![0, 0, 0, 1, 0x41, 0x9A],
+            vec![0, 0, 0, 1, 0x41, 0x9A],
+            vec![0, 0, 0, 1, 0x41, 0x40],
+        ])
+    }
+
+    #[test]
+    fn resolve_frame_maps_pictures_to_nal_units() {
+        let video = hand_built_video();
+        assert_eq!(video.frame_count(), 3);
+        assert_eq!(video.resolve_frame(0), Some(2));
+        assert_eq!(video.resolve_frame(2), Some(4));
+        assert_eq!(video.resolve_frame(3), None);
+        assert_eq!(video.resolve_frame(usize::MAX), None);
+        // The last picture runs to the end of the buffer, including its second slice
+        assert_eq!(video.resolve_frame_end(3), 6);
+        assert_eq!(video.resolve_frame_end(1), 3);
+    }
+
+    #[test]
+    fn loops_restart_from_a_clean_keyframe() {
+        // P pictures from the middle of the clip in front of two GOPs, so the loop starts after them
+        // Wrapping to the start of the buffer would decode them on top of the last frame of the previous loop
+        let nals: Vec<Vec<u8>> = nal_units(include_bytes!("../example/assets/test.h264")).map(|nal| nal.to_vec()).collect();
+        let leading = nals[100..104].iter();
+        let first_gop = nals[253..265].iter();
+        let second_gop = nals[505..511].iter();
+        let video = H264Video::from_nal_units(leading.chain(first_gop).chain(second_gop).cloned().collect());
+        assert_eq!(video.loop_start, 4);
+        assert_eq!(video.frame_count(), 14);
+        assert_eq!(video.keyframes(), &[0, 10]);
+
+        let mut images = Assets::<Image>::default();
+        let mut decoder = test_decoder(&mut images);
+        decoder.repeat = true;
+        decoder.apply_video(&video);
+        decoder.frame_idx = video.loop_start;
+
+        let mut first_frames = Vec::new();
+        for _ in 0..100 {
+            if first_frames.len() >= 2 {
+                break;
+            }
+            let queued = decoder.queued_frames();
+            decoder.fill_buffer(&video, None, queued);
+            while let Some(frame) = decoder.try_take_frame() {
+                if frame.position.is_some_and(|position| position.picture == 0) {
+                    first_frames.push(frame);
+                }
+            }
+        }
+        assert!(first_frames.len() >= 2);
+        let (loop_1, loop_2) = (&first_frames[0], &first_frames[1]);
+        assert!(loop_1.position.unwrap().loop_idx < loop_2.position.unwrap().loop_idx);
+        assert_eq!(loop_1.data(), loop_2.data());
+    }
+
+    #[test]
+    fn index_rebuilds_the_scanned_video() {
+        let bytes = solid_color_h264(16, 16, 5, Color::WHITE).unwrap();
+        let index = h264_index(&bytes).unwrap();
+        let index = VideoIndex::decode(&index, &bytes).unwrap();
+        let indexed = H264Video::from_index(split_at_offsets(&bytes, &index.nal_offsets), index);
+        let scanned = H264Video::from_annex_b(&bytes);
+        assert_eq!(indexed.buffer.len(), scanned.buffer.len());
+        assert_eq!(indexed.pictures, scanned.pictures);
+        assert_eq!(indexed.keyframes, scanned.keyframes);
+        assert_eq!(indexed.parameter_sets, scanned.parameter_sets);
+        assert_eq!(indexed.loop_start, scanned.loop_start);
+        // An index of another file is rejected, even if it has the same size
+        let mut other = bytes.clone();
+        *other.last_mut().unwrap() ^= 1;
+        assert!(VideoIndex::decode(&h264_index(&bytes).unwrap(), &other).is_none());
+    }
+
+    #[test]
+    fn rbsp_prefix_unescapes_only_the_start() {
+        let nal = [0, 0, 0, 1, 0x65, 0, 0, 3, 1, 0xFF, 0xFF];
+        assert_eq!(nal_rbsp(&nal), Some(vec![0, 0, 1, 0xFF, 0xFF]));
+        assert_eq!(nal_rbsp_prefix(&nal, 3), Some(vec![0, 0, 1]));
+    }
+}